@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+
+use cgmath::Vector2;
+use glutin::VirtualKeyCode;
+
+type Qr<T> = Vector2<T>;
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    pub keys: KeysConfig,
+    pub velocity: Velocity,
+    pub expression: Expression,
+    pub midi_in: MidiIn,
+}
+
+/// What dragging inside a held hex does
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct Expression {
+    /// Pitch bend in cents for a horizontal drag by one hex size, MPE only
+    pub bend: f32,
+    /// Send the vertical drag as timbre (CC74) in MPE, as polyphonic aftertouch otherwise
+    pub timbre: bool,
+}
+
+impl Default for Expression {
+    fn default() -> Self {
+        Expression {
+            bend: 100.0,
+            timbre: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum Velocity {
+    Fixed(u8),
+    /// Louder towards the center of a hex, `curve` is the exponent of the response
+    Center { min: u8, max: u8, curve: f32 },
+}
+
+impl Default for Velocity {
+    fn default() -> Self {
+        Velocity::Fixed(64)
+    }
+}
+
+impl Velocity {
+    /// Velocity of a hit at `offset` from the center of a hex, in hex sizes
+    pub fn velocity(&self, offset: Vector2<f32>) -> u8 {
+        use cgmath::InnerSpace;
+
+        let v = match *self {
+            Velocity::Fixed(v) => v,
+            Velocity::Center { min, max, curve } => {
+                let x = (1.0 - offset.magnitude()).max(0.0).min(1.0);
+                let v = min as f32 + (max as f32 - min as f32) * x.powf(curve);
+                v.round() as u8
+            },
+        };
+
+        // Velocity 0 is a note off
+        v.max(1).min(127)
+    }
+}
+
+/// Hexes of the keys of a MIDI controller
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct MidiIn {
+    /// Hexes by channel (from 1 to 16) and key, like `{(1, 60): (0, 0)}`.
+    /// Keys left out play the step of the key, with key 60 as step 0
+    keys: HashMap<(u8, u8), (i32, i32)>,
+}
+
+impl MidiIn {
+    /// The channel is from 0 to 15 here
+    pub fn hex(&self, channel: u8, key: u8) -> Option<Qr<i32>> {
+        self.keys.get(&(channel + 1, key)).map(|&(q, r)| Vector2::new(q, r))
+    }
+}
+
+/// Rows of the computer keyboard laid onto the grid
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct KeysConfig {
+    /// Hex of the first key of the bottom row
+    origin: (i32, i32),
+    /// Rows from the top to the bottom, spaces leave a hex out
+    rows: Vec<String>,
+}
+
+impl Default for KeysConfig {
+    fn default() -> Self {
+        KeysConfig {
+            origin: (-5, 1),
+            rows: vec![
+                "1234567890-=".into(),
+                "QWERTYUIOP[]".into(),
+                "ASDFGHJKL;'".into(),
+                "ZXCVBNM,./".into(),
+            ],
+        }
+    }
+}
+
+fn key_code(c: char) -> Option<VirtualKeyCode> {
+    use glutin::VirtualKeyCode::*;
+
+    const LETTERS: [VirtualKeyCode; 26] = [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z
+    ];
+    const DIGITS: [VirtualKeyCode; 10] = [
+        Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9
+    ];
+
+    Some(match c.to_ascii_uppercase() {
+        c @ 'A'..='Z' => LETTERS[c as usize - 'A' as usize],
+        c @ '0'..='9' => DIGITS[c as usize - '0' as usize],
+        '-' => Minus,
+        '=' => Equals,
+        '[' => LBracket,
+        ']' => RBracket,
+        ';' => Semicolon,
+        '\'' => Apostrophe,
+        ',' => Comma,
+        '.' => Period,
+        '/' => Slash,
+        '\\' => Backslash,
+        '`' => Grave,
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Keymap {
+    pub keys: Vec<(VirtualKeyCode, Qr<i32>)>,
+}
+
+impl Keymap {
+    pub fn new(config: &KeysConfig) -> Result<Self, String> {
+        let (q0, r0) = config.origin;
+        let mut keys = vec![];
+
+        // Every row is up-left of the one below it, like on the keyboard
+        for (i, row) in config.rows.iter().rev().enumerate() {
+            for (j, c) in row.chars().enumerate() {
+                if c == ' ' {
+                    continue
+                }
+
+                let vk = key_code(c).ok_or_else(|| format!("unknown key `{}` in the keymap", c))?;
+                keys.push((vk, Vector2::new(q0 + j as i32, r0 - i as i32)))
+            }
+        }
+
+        Ok(Keymap { keys })
+    }
+
+    pub fn hex(&self, vk: VirtualKeyCode) -> Option<Qr<i32>> {
+        self.keys.iter().find(|k| k.0 == vk).map(|k| k.1)
+    }
+}
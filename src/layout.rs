@@ -1,3 +1,6 @@
+use std::{fmt, io};
+use std::path::{Path, PathBuf};
+
 use cgmath::{Vector3, Rad, Deg};
 
 macro_rules! rgb {
@@ -10,12 +13,145 @@ macro_rules! rgb {
     }
 }
 
+const MIDDLE_C: f32 = 261.625565;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Layout {
+    pub name: String,
     pub angle: Rad<f32>,
     pub q_steps: i32,
     pub r_steps: i32,
     pub colors: Vec<Vector3<f32>>,
+    pub tuning: Tuning,
+    /// Names of the steps, one for every color
+    pub names: Option<Vec<String>>,
+    pub naming: Naming,
+}
+
+/// How the steps are named
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Naming {
+    /// Ups-and-downs notation built from the chain of fifths
+    UpsAndDowns,
+    /// The names given in the layout
+    Names,
+    /// Step numbers
+    Steps,
+}
+
+/// Division of a period, anchored by the frequency of a reference step
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuning {
+    pub divisions: i32,
+    /// Period in cents
+    pub period: f32,
+    /// Cents of every step of the period, equal division if there's none
+    pub scale: Option<Vec<f32>>,
+    pub ref_step: i32,
+    pub ref_freq: f32,
+}
+
+impl Tuning {
+    /// Divisions of the octave with step 0 at the middle C
+    pub fn edo(divisions: i32) -> Self {
+        Tuning {
+            divisions,
+            period: 1200.0,
+            scale: None,
+            ref_step: 0,
+            ref_freq: MIDDLE_C,
+        }
+    }
+
+    /// Size of one step in cents, on average for unequal scales
+    pub fn step_size(&self) -> f32 {
+        self.period / self.divisions as f32
+    }
+
+    /// Distance from the reference step in cents
+    pub fn cents(&self, step: i32) -> f32 {
+        self.absolute_cents(step) - self.absolute_cents(self.ref_step)
+    }
+
+    /// Distance from step 0 in cents
+    fn absolute_cents(&self, step: i32) -> f32 {
+        let scale = match self.scale {
+            Some(ref scale) => scale,
+            None => return step as f32 * self.step_size(),
+        };
+
+        let n = self.divisions;
+        let (period, degree) = match step % n {
+            d if d >= 0 => (step / n, d),
+            d => (step / n - 1, n + d),
+        };
+
+        period as f32 * self.period + scale[degree as usize]
+    }
+
+    pub fn freq(&self, step: i32) -> f32 {
+        self.ref_freq * (self.cents(step) / 1200.0).exp2()
+    }
+
+    /// The step closest to a just fifth
+    pub fn fifth(&self) -> i32 {
+        closest_step(self, 701.955)
+    }
+
+    /// Fractional MIDI key number of the step
+    pub fn pitch(&self, step: i32) -> f32 {
+        69.0 + 12.0 * (self.freq(step) / 440.0).log2()
+    }
+
+    /// Distance in cents from the start of the period the step is in
+    pub fn period_cents(&self, step: i32) -> f32 {
+        let cents = self.absolute_cents(step) % self.period;
+        if cents < 0.0 { cents + self.period } else { cents }
+    }
+
+    /// Names of the steps of a period in ups-and-downs notation, step 0 is C
+    pub fn note_names(&self) -> Vec<String> {
+        let n = self.divisions;
+        let d = Diatonic::new(self);
+        // The shortest way from one step to another, up or down
+        let between = |from: i32, to: i32| match (to - from) % n {
+            u if 2 * u > n => u - n,
+            u if 2 * u <= -n => u + n,
+            u => u,
+        };
+        // Sharps don't do anything when the apotome is no step at all
+        let accidentals = if d.apotome == 0 { 0..1 } else { -1..2 };
+
+        (0..n).map(|step| {
+            let mut spellings = vec![];
+            for k in -1..6 {
+                for sharps in accidentals.clone() {
+                    let ups = between(k * d.fifth + sharps * d.apotome, step);
+                    spellings.push((k, sharps, ups))
+                }
+            }
+
+            // The fewest symbols, then the closest letter and the fewest ups and downs,
+            // sharps before flats
+            let (k, sharps, ups) = spellings.into_iter()
+                .min_by_key(|&(k, s, u)| (s.abs() + u.abs(), between(k * d.fifth, step).abs(), u.abs(), s < 0))
+                .unwrap();
+
+            let mut name = String::new();
+            let arrow = if ups > 0 { "^" } else { "v" };
+            for _ in 0..ups.abs() {
+                name.push_str(arrow)
+            }
+            name.push("FCGDAEB".as_bytes()[(k + 1) as usize] as char);
+            match sharps {
+                1 => name.push('#'),
+                -1 => name.push('b'),
+                _ => (),
+            }
+
+            name
+        }).collect()
+    }
 }
 
 #[derive(Deserialize)]
@@ -35,12 +171,54 @@ impl From<Angle> for Rad<f32> {
     }
 }
 
+#[derive(Deserialize)]
+enum Interval {
+    Ratio(u32, u32),
+    Cents(f32),
+}
+
+impl Interval {
+    fn cents(&self) -> f32 {
+        match *self {
+            Interval::Ratio(n, d) =>
+                1200.0 * (n as f32 / d as f32).log2(),
+            Interval::Cents(c) =>
+                c,
+        }
+    }
+}
+
+fn default_period() -> Interval {
+    Interval::Ratio(2, 1)
+}
+
+fn default_ref_freq() -> f32 {
+    MIDDLE_C
+}
+
 #[derive(Deserialize)]
 pub struct LayoutConfig {
+    /// The name of the file by default
+    #[serde(default)]
+    name: Option<String>,
     angle: Angle,
     q_steps: i32,
     r_steps: i32,
-    colors: Vec<[u8; 3]>
+    colors: Vec<[u8; 3]>,
+    /// Number of steps in a period, the number of colors by default
+    #[serde(default)]
+    divisions: Option<i32>,
+    #[serde(default = "default_period")]
+    period: Interval,
+    #[serde(default)]
+    ref_step: i32,
+    #[serde(default = "default_ref_freq")]
+    ref_freq: f32,
+    #[serde(default)]
+    names: Option<Vec<String>>,
+    /// The given names if there are some, ups-and-downs otherwise
+    #[serde(default)]
+    naming: Option<Naming>,
 }
 
 impl From<LayoutConfig> for Layout {
@@ -48,17 +226,529 @@ impl From<LayoutConfig> for Layout {
         let angle = source.angle.into();
         let (q_steps, r_steps) = (source.q_steps, source.r_steps);
         let colors = source.colors.iter().map(|c| rgb!(c[0], c[1], c[2])).collect();
+        let tuning = Tuning {
+            divisions: source.divisions.unwrap_or(source.colors.len() as i32),
+            period: source.period.cents(),
+            scale: None,
+            ref_step: source.ref_step,
+            ref_freq: source.ref_freq,
+        };
+
+        let naming = source.naming.unwrap_or(
+            if source.names.is_some() { Naming::Names } else { Naming::UpsAndDowns }
+        );
 
         Layout {
+            name: source.name.unwrap_or_default(),
             angle,
             q_steps, r_steps,
-            colors
+            colors,
+            tuning,
+            names: source.names,
+            naming,
+        }
+    }
+}
+
+/// What is wrong with a layout file
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Ron(::ron::de::Error),
+    NoColors,
+    /// Steps in a period must be positive
+    Divisions(i32),
+    /// Every hex would play the same step
+    NoSteps,
+    /// `naming` is `Names`, but there are no names
+    NoNames,
+    Names { names: usize, colors: usize },
+    /// A directory without layouts
+    NoLayouts,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) if e.kind() == io::ErrorKind::NotFound =>
+                write!(f, "file not found"),
+            Error::Io(ref e) =>
+                write!(f, "{}", e),
+            Error::Ron(ref e) =>
+                write!(f, "{}", e),
+            Error::NoColors =>
+                write!(f, "`colors` is empty, there has to be a color for every step"),
+            Error::Divisions(n) =>
+                write!(f, "`divisions` is {}, but it has to be positive", n),
+            Error::NoSteps =>
+                write!(f, "`q_steps` and `r_steps` are both 0, every hex would play the same note"),
+            Error::NoNames =>
+                write!(f, "`naming` is `Names`, but the layout has no `names`"),
+            Error::Names { names, colors } =>
+                write!(f, "there are {} names for {} colors, every color needs a name", names, colors),
+            Error::NoLayouts =>
+                write!(f, "there are no .ron files in the directory"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<::ron::de::Error> for Error {
+    fn from(e: ::ron::de::Error) -> Self {
+        Error::Ron(e)
+    }
+}
+
+pub fn read<P: AsRef<Path>>(path: P) -> Result<String, Error> {
+    use std::io::Read;
+
+    let mut src = String::new();
+    ::std::fs::File::open(path)?.read_to_string(&mut src)?;
+
+    Ok(src)
+}
+
+/// The file itself, or the RON files of a directory in alphabetical order
+pub fn layout_files<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>, Error> {
+    let path = path.as_ref();
+    if !path.is_dir() {
+        return Ok(vec![path.into()])
+    }
+
+    let mut files = vec![];
+    for entry in ::std::fs::read_dir(path)? {
+        let file = entry?.path();
+        if file.extension().map_or(false, |e| e == "ron") {
+            files.push(file)
         }
     }
+    files.sort();
+
+    if files.is_empty() { Err(Error::NoLayouts) } else { Ok(files) }
+}
+
+/// Read a RON layout and check it
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Layout, Error> {
+    let path = path.as_ref();
+    let config: LayoutConfig = ::ron::de::from_str(&read(path)?)?;
+    let mut layout: Layout = config.into();
+    layout.validate()?;
+
+    if layout.name.is_empty() {
+        layout.name = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default()
+    }
+
+    Ok(layout)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new<S: Into<String>>(line: usize, message: S) -> Self {
+        ParseError { line, message: message.into() }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// A Scala scale: pitches of the degrees above the unison, the last one is the period
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scale {
+    pub description: String,
+    pub pitches: Vec<f32>,
+}
+
+/// The part of a Scala keyboard mapping that makes sense on a hex grid
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyboardMap {
+    /// Mapping size and the line it is on, 0 for a linear mapping
+    pub size: (usize, usize),
+    pub middle_note: i32,
+    pub ref_note: i32,
+    pub ref_freq: f32,
+}
+
+/// Lines that aren't comments, numbered from 1
+fn scala_lines<'a>(src: &'a str) -> Box<Iterator<Item=(usize, &'a str)> + 'a> {
+    Box::new(
+        src.lines().enumerate()
+            .map(|(i, l)| (i + 1, l))
+            .filter(|&(_, l)| !l.starts_with('!'))
+    )
+}
+
+fn first_word(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or("")
+}
+
+fn parse_number<T: ::std::str::FromStr>(line: (usize, &str), what: &str) -> Result<T, ParseError> {
+    first_word(line.1).parse()
+        .map_err(|_| ParseError::new(line.0, format!("expected {}, found `{}`", what, line.1.trim())))
+}
+
+fn parse_pitch(line: (usize, &str)) -> Result<f32, ParseError> {
+    let word = first_word(line.1);
+    let error = || ParseError::new(line.0, format!("invalid pitch `{}`", word));
+
+    let cents =
+        if word.contains('.') {
+            word.parse().map_err(|_| error())?
+        } else {
+            let mut parts = word.splitn(2, '/');
+            let n: u64 = parts.next().unwrap().parse().map_err(|_| error())?;
+            let d: u64 = parts.next().unwrap_or("1").parse().map_err(|_| error())?;
+            if n == 0 || d == 0 {
+                return Err(error())
+            }
+
+            1200.0 * (n as f64 / d as f64).log2() as f32
+        };
+
+    Ok(cents)
+}
+
+pub fn parse_scl(src: &str) -> Result<Scale, ParseError> {
+    let mut lines = scala_lines(src);
+    let eof = |what: &str| ParseError::new(src.lines().count(), format!("unexpected end of file, expected {}", what));
+
+    let description = lines.next().ok_or_else(|| eof("a description"))?.1.trim().to_string();
+    let mut lines = lines.filter(|&(_, l)| !l.trim().is_empty());
+
+    let count_line = lines.next().ok_or_else(|| eof("the number of notes"))?;
+    let count: usize = parse_number(count_line, "the number of notes")?;
+    if count == 0 {
+        return Err(ParseError::new(count_line.0, "a scale must have at least one note"))
+    }
+
+    let mut pitches = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = lines.next().ok_or_else(|| eof("a pitch"))?;
+        pitches.push(parse_pitch(line)?)
+    }
+
+    if *pitches.last().unwrap() <= 0.0 {
+        return Err(ParseError::new(count_line.0, "the period must be greater than the unison"))
+    }
+
+    Ok(Scale { description, pitches })
+}
+
+pub fn parse_kbm(src: &str) -> Result<KeyboardMap, ParseError> {
+    let mut lines = scala_lines(src).filter(|&(_, l)| !l.trim().is_empty());
+    let mut next = |what: &str| lines.next()
+        .ok_or_else(|| ParseError::new(src.lines().count(), format!("unexpected end of file, expected {}", what)));
+
+    let size_line = next("the map size")?;
+    let size: usize = parse_number(size_line, "the map size")?;
+    let _first: i32 = parse_number(next("the first MIDI note")?, "the first MIDI note")?;
+    let _last: i32 = parse_number(next("the last MIDI note")?, "the last MIDI note")?;
+    let middle_note = parse_number(next("the middle note")?, "the middle note")?;
+    let ref_note = parse_number(next("the reference note")?, "the reference note")?;
+    let ref_freq = parse_number(next("the reference frequency")?, "the reference frequency")?;
+    let octave_line = next("the octave degree")?;
+    let octave: usize = parse_number(octave_line, "the octave degree")?;
+
+    if size != 0 && octave != size {
+        return Err(ParseError::new(octave_line.0, "only linear keyboard mappings are supported"))
+    }
+    for degree in 0..size {
+        let line = next("a mapping entry")?;
+        if first_word(line.1) != degree.to_string() {
+            return Err(ParseError::new(line.0, "only linear keyboard mappings are supported"))
+        }
+    }
+
+    Ok(KeyboardMap {
+        size: (size, size_line.0),
+        middle_note, ref_note, ref_freq,
+    })
+}
+
+/// Shade the degrees like the keys of a piano they are closest to
+fn piano_colors(scale: &[f32]) -> Vec<Vector3<f32>> {
+    const BLACK_KEYS: [bool; 12] = [
+        false, true, false, true, false, false, true, false, true, false, true, false
+    ];
+
+    scale.iter().map(|&cents| {
+        let key = (cents / 100.0).round() as i32;
+        let off = (cents - 100.0 * key as f32).abs();
+        match key % 12 {
+            _ if off > 25.0 => rgb!(0x9b, 0x9b, 0x9b),
+            k if BLACK_KEYS[((k + 12) % 12) as usize] => rgb!(0x4b, 0x4b, 0x4b),
+            _ => rgb!(0xef, 0xef, 0xef),
+        }
+    }).collect()
+}
+
+/// The step that is the closest to the interval
+fn closest_step(tuning: &Tuning, cents: f32) -> i32 {
+    (1..tuning.divisions + 1)
+        .min_by(|&a, &b| {
+            let da = (tuning.absolute_cents(a) - cents).abs();
+            let db = (tuning.absolute_cents(b) - cents).abs();
+            da.partial_cmp(&db).unwrap()
+        })
+        .unwrap()
+}
+
+fn gcd(a: i32, b: i32) -> i32 {
+    if b == 0 { a.abs() } else { gcd(b, a % b) }
+}
+
+/// Diatonic intervals of a tuning, in steps, built from its best fifth
+struct Diatonic {
+    fifth: i32,
+    tone: i32,
+    limma: i32,
+    apotome: i32,
+}
+
+impl Diatonic {
+    fn new(tuning: &Tuning) -> Self {
+        let n = tuning.divisions;
+        let fifth = tuning.fifth();
+
+        Diatonic {
+            fifth,
+            tone: 2 * fifth - n,
+            limma: 3 * n - 5 * fifth,
+            apotome: 7 * fifth - 4 * n,
+        }
+    }
+
+    /// Whole tones along q, the larger semitone along r. When the fifth comes from a
+    /// smaller EDO (like the one of 72-EDO from 12-EDO) the two have a common factor
+    /// and would skip most steps, then r moves by single steps instead.
+    fn mapping(&self) -> (Rad<f32>, i32, i32) {
+        let angle =
+            if self.limma >= self.apotome { Deg(16.102113752) }
+            else { Deg(-16.102113752) };
+        let semitone = self.limma.max(self.apotome);
+        let r_steps = if gcd(self.tone, semitone) == 1 { semitone } else { 1 };
+
+        (angle.into(), self.tone, r_steps)
+    }
+}
+
+/// Classic isomorphic layouts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Preset {
+    Bosanquet,
+    WickiHayden,
+    HarmonicTable,
+    Janko,
+    Gerhard,
+}
+
+impl Preset {
+    pub fn all() -> &'static [Preset] {
+        use self::Preset::*;
+        &[Bosanquet, WickiHayden, HarmonicTable, Janko, Gerhard]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::Bosanquet => "bosanquet",
+            Preset::WickiHayden => "wicki-hayden",
+            Preset::HarmonicTable => "harmonic-table",
+            Preset::Janko => "janko",
+            Preset::Gerhard => "gerhard",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Preset::all().iter().cloned().find(|p| p.name() == name)
+    }
+
+    // q points right and r down-right before the rotation, so q - r is up-right and -r is up-left
+    fn mapping(self, d: &Diatonic) -> (Rad<f32>, i32, i32) {
+        match self {
+            // Whole tones to the right, semitones on the diagonals
+            Preset::Bosanquet => d.mapping(),
+            // Whole tones to the right, fifths up-right, fourths up-left
+            Preset::WickiHayden => (Deg(0.0).into(), d.tone, d.tone - d.fifth),
+            // Fifths up, major thirds up-right, minor thirds up-left
+            Preset::HarmonicTable => (Deg(90.0).into(), d.fifth, 2 * d.tone),
+            // Whole tones to the right, semitones up-right
+            Preset::Janko => (Deg(0.0).into(), d.tone, d.apotome),
+            // Chromatic semitones up, major thirds up-right, minor thirds down-right
+            Preset::Gerhard => (Deg(90.0).into(), d.apotome, 2 * d.tone),
+        }
+    }
+}
+
+impl Layout {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.colors.is_empty() {
+            return Err(Error::NoColors)
+        }
+        if self.tuning.divisions <= 0 {
+            return Err(Error::Divisions(self.tuning.divisions))
+        }
+        if self.q_steps == 0 && self.r_steps == 0 {
+            return Err(Error::NoSteps)
+        }
+
+        match (self.naming, &self.names) {
+            (Naming::Names, &None) =>
+                Err(Error::NoNames),
+            (_, &Some(ref names)) if names.len() != self.colors.len() =>
+                Err(Error::Names { names: names.len(), colors: self.colors.len() }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Names of the steps, repeating every `len()` steps
+    pub fn note_names(&self) -> Vec<String> {
+        match (self.naming, &self.names) {
+            (Naming::Names, &Some(ref names)) => names.clone(),
+            (Naming::Steps, _) => (0..self.tuning.divisions).map(|s| s.to_string()).collect(),
+            _ => self.tuning.note_names(),
+        }
+    }
+
+    /// Steps between the ones the grid can play, more than 1 when `q_steps` and `r_steps`
+    /// have a common factor and the others are missing
+    pub fn step_stride(&self) -> i32 {
+        gcd(self.q_steps, self.r_steps)
+    }
+
+    /// The same tuning and colors with the steps mapped the preset way. Presets that are
+    /// built from thirds and fifths can skip steps, like the harmonic table in 31-EDO
+    pub fn with_preset(self, preset: Preset) -> Layout {
+        let (angle, q_steps, r_steps) = preset.mapping(&Diatonic::new(&self.tuning));
+
+        Layout {
+            name: format!("{}, {}", self.name, preset.name()),
+            angle,
+            q_steps, r_steps,
+            ..self
+        }
+    }
+}
+
+/// The colors of the builtin layouts: when sharps are flats those of a piano,
+/// when the sharps are lower than the flats the ones of 31-EDO, otherwise the ones of 53-EDO
+fn fifths_colors(divisions: i32, diatonic: &Diatonic) -> Vec<Vector3<f32>> {
+    let n = divisions;
+    let wrap = |s: i32| match s % n {
+        p if p >= 0 => p,
+        p => n + p,
+    };
+    let naturals: Vec<i32> = (-1..6).map(|k| wrap(k * diatonic.fifth)).collect();
+    let near = |step: i32, by: i32| naturals.iter().any(|&nat| wrap(nat + by) == step);
+    let (sharp, flat) = (diatonic.apotome, -diatonic.apotome);
+
+    (0..n).map(|step| {
+        if diatonic.apotome == diatonic.limma {
+            if near(step, 0) { rgb!(0xef, 0xef, 0xef) }
+            else if near(step, sharp) { rgb!(0x4b, 0x4b, 0x4b) }
+            else if near(step, 1) { rgb!(0xff, 0x9f, 0x41) }
+            else if near(step, -1) { rgb!(0x7b, 0x7b, 0x7b) }
+            else { rgb!(0x9b, 0x9b, 0x9b) }
+        } else if diatonic.apotome < diatonic.limma {
+            if near(step, 0) { rgb!(0xff, 0xff, 0xff) }
+            else if near(step, sharp) { rgb!(0xcf, 0xcf, 0xcf) }
+            else if near(step, flat) { rgb!(0xbb, 0xaa, 0x93) }
+            else if near(step, 1) { rgb!(0xff, 0x9f, 0x41) }
+            else if near(step, -1) { rgb!(0x7b, 0x7b, 0x7b) }
+            else { rgb!(0x9b, 0x9b, 0x9b) }
+        } else {
+            // Commas up are warm and down are cool, sharps and flats alternate with a comma between
+            if near(step, 0) { rgb!(0xff, 0xff, 0xff) }
+            else if near(step, 1) { rgb!(0xc3, 0xb7, 0xab) }
+            else if near(step, -1) { rgb!(0xab, 0xb7, 0xc3) }
+            else if near(step, 2) || near(step, -2) { rgb!(0xe7, 0xe7, 0xe7) }
+            else if near(step, sharp) || near(step, flat - 1) { rgb!(0x87, 0x87, 0x87) }
+            else if near(step, flat) || near(step, sharp + 1) { rgb!(0xff, 0x9f, 0x40) }
+            else { rgb!(0x9b, 0x9b, 0x9b) }
+        }
+    }).collect()
+}
+
+/// A layout for any EDO, laid out like the builtin ones
+pub fn generate(divisions: i32) -> Layout {
+    let tuning = Tuning::edo(divisions);
+    let diatonic = Diatonic::new(&tuning);
+    let (angle, q_steps, r_steps) = diatonic.mapping();
+
+    Layout {
+        name: format!("{}-EDO", divisions),
+        angle,
+        q_steps, r_steps,
+        colors: fifths_colors(divisions, &diatonic),
+        tuning,
+        names: None,
+        naming: Naming::UpsAndDowns,
+    }
+}
+
+/// The builtin layout if there's one, a generated one otherwise
+pub fn edo_layout(divisions: i32) -> Layout {
+    match divisions {
+        12 => edo12_layout(),
+        31 => edo31_layout(),
+        53 => edo53_layout(),
+        n => generate(n),
+    }
+}
+
+pub fn scala_layout(scale: &Scale, kbm: Option<&KeyboardMap>) -> Result<Layout, ParseError> {
+    let divisions = scale.pitches.len() as i32;
+    let period = *scale.pitches.last().unwrap();
+    let mut degrees = vec![0.0];
+    degrees.extend(&scale.pitches[..scale.pitches.len() - 1]);
+
+    let (ref_step, ref_freq) = match kbm {
+        Some(kbm) => {
+            let (size, line) = kbm.size;
+            if size != 0 && size != scale.pitches.len() {
+                return Err(ParseError::new(line, format!(
+                    "the mapping has {} keys, but the scale has {} notes", size, scale.pitches.len()
+                )))
+            }
+
+            (kbm.ref_note - kbm.middle_note, kbm.ref_freq)
+        },
+        None => (0, MIDDLE_C),
+    };
+
+    let colors = piano_colors(&degrees);
+    let tuning = Tuning {
+        divisions, period,
+        scale: Some(degrees),
+        ref_step, ref_freq,
+    };
+    let (angle, q_steps, r_steps) = Diatonic::new(&tuning).mapping();
+
+    Ok(Layout {
+        name: scale.description.clone(),
+        angle,
+        q_steps, r_steps,
+        colors,
+        tuning,
+        names: None,
+        naming: Naming::UpsAndDowns,
+    })
 }
 
 pub fn edo31_layout() -> Layout {
     Layout {
+        name: "31-EDO".into(),
         angle: Deg(16.102113752).into(),
         q_steps: 5,
         r_steps: 3,
@@ -95,11 +785,15 @@ pub fn edo31_layout() -> Layout {
             rgb!(0xbb, 0xaa, 0x93),
             rgb!(0xcf, 0xcf, 0xcf),
         ],
+        tuning: Tuning::edo(31),
+        names: None,
+        naming: Naming::UpsAndDowns,
     }
 }
 
 pub fn edo12_layout() -> Layout {
     Layout {
+        name: "12-EDO".into(),
         angle: Deg(16.102113752).into(),
         q_steps: 2,
         r_steps: 1,
@@ -117,11 +811,15 @@ pub fn edo12_layout() -> Layout {
             rgb!(0x4b, 0x4b, 0x4b),
             rgb!(0xef, 0xef, 0xef),
         ],
+        tuning: Tuning::edo(12),
+        names: None,
+        naming: Naming::UpsAndDowns,
     }
 }
 
 pub fn edo53_layout() -> Layout {
     Layout {
+        name: "53-EDO".into(),
         angle: Deg(-16.102113752).into(),
         q_steps: 9,
         r_steps: 5,
@@ -180,5 +878,218 @@ pub fn edo53_layout() -> Layout {
             rgb!(0xe7, 0xe7, 0xe7),
             rgb!(0xab, 0xb7, 0xc3),
         ],
+        tuning: Tuning::edo(53),
+        names: None,
+        naming: Naming::UpsAndDowns,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_generated(builtin: Layout) {
+        let layout = generate(builtin.tuning.divisions);
+
+        assert_eq!(layout.angle, builtin.angle);
+        assert_eq!((layout.q_steps, layout.r_steps), (builtin.q_steps, builtin.r_steps));
+        assert_eq!(layout.colors, builtin.colors);
+        assert_eq!(layout.name, builtin.name);
+    }
+
+    #[test]
+    fn generates_12() {
+        assert_generated(edo12_layout())
+    }
+
+    #[test]
+    fn generates_31() {
+        assert_generated(edo31_layout())
+    }
+
+    #[test]
+    fn generates_53() {
+        assert_generated(edo53_layout())
+    }
+
+    fn assert_every_step(divisions: i32) {
+        let layout = generate(divisions);
+
+        assert_eq!(gcd(layout.q_steps, layout.r_steps), 1, "{}-EDO", divisions);
+    }
+
+    #[test]
+    fn every_step_of_19_22_41() {
+        assert_every_step(19);
+        assert_every_step(22);
+        assert_every_step(41);
+    }
+
+    #[test]
+    fn every_step_of_72() {
+        let layout = generate(72);
+
+        assert_eq!((layout.q_steps, layout.r_steps), (12, 1));
+    }
+
+    #[test]
+    fn every_step_up_to_100() {
+        for divisions in 1..101 {
+            assert_every_step(divisions)
+        }
+    }
+
+    #[test]
+    fn presets_in_31_and_41() {
+        for &divisions in &[31, 41] {
+            let strides: Vec<_> = Preset::all().iter()
+                .map(|&p| generate(divisions).with_preset(p).step_stride())
+                .collect();
+
+            // The thirds of the harmonic table and Gerhard are both an even number of steps
+            assert_eq!(strides, vec![1, 1, 2, 1, 2], "{}-EDO", divisions);
+        }
+    }
+
+    const TET12: &str = "\
+! 12tet.scl
+!
+12 tone equal temperament
+ 12
+!
+ 100.0
+ 200.0
+ 300.0
+ 400.0
+ 500.0
+ 600.0
+ 700.0
+ 800.0
+ 900.0
+ 1000.0
+ 1100.0
+ 1200.0
+";
+
+    const PTOLEMY: &str = "\
+! ptolemy.scl
+!
+Ptolemy's intense diatonic
+ 7
+!
+ 9/8
+ 5/4
+ 4/3
+ 3/2
+ 5/3
+ 15/8
+ 2
+";
+
+    const A440: &str = "\
+! example.kbm
+! Size of map:
+12
+! First MIDI note number to retune:
+0
+! Last MIDI note number to retune:
+127
+! Middle note where the first entry of the mapping is mapped to:
+60
+! Reference note for which frequency is given:
+69
+! Frequency to tune the above note to:
+440.0
+! Scale degree to consider as formal octave:
+12
+! Mapping.
+0
+1
+2
+3
+4
+5
+6
+7
+8
+9
+10
+11
+";
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 0.01, "{} != {}", a, b)
+    }
+
+    #[test]
+    fn parses_cents() {
+        let scale = parse_scl(TET12).unwrap();
+
+        assert_eq!(scale.description, "12 tone equal temperament");
+        assert_eq!(scale.pitches, (1..13).map(|i| 100.0 * i as f32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn parses_ratios() {
+        let scale = parse_scl(PTOLEMY).unwrap();
+        let cents = [203.91, 386.31, 498.04, 701.96, 884.36, 1088.27, 1200.0];
+
+        assert_eq!(scale.pitches.len(), cents.len());
+        for (&a, &b) in scale.pitches.iter().zip(&cents) {
+            assert_close(a, b)
+        }
+    }
+
+    #[test]
+    fn parses_kbm() {
+        let kbm = parse_kbm(A440).unwrap();
+
+        assert_eq!(kbm, KeyboardMap { size: (12, 3), middle_note: 60, ref_note: 69, ref_freq: 440.0 });
+    }
+
+    #[test]
+    fn tunes_a440() {
+        let layout = scala_layout(&parse_scl(TET12).unwrap(), Some(&parse_kbm(A440).unwrap())).unwrap();
+        let tuning = &layout.tuning;
+
+        assert_close(tuning.freq(9), 440.0);
+        assert_close(tuning.freq(21), 880.0);
+        assert_close(tuning.freq(0), 261.63);
+    }
+
+    #[test]
+    fn too_few_pitches() {
+        let src = "Short\n 3\n 100.0\n 200.0\n";
+
+        let e = parse_scl(src).unwrap_err();
+        assert_eq!(e.line, 4);
+        assert!(e.message.contains("unexpected end of file"))
+    }
+
+    #[test]
+    fn bad_pitch() {
+        let src = "Bad\n 2\n 100.0\n cents\n";
+
+        assert_eq!(parse_scl(src).unwrap_err(), ParseError::new(4, "invalid pitch `cents`"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn zero_ratio() {
+        let src = "! zero.scl\nZero\n 2\n 0/1\n 2/1\n";
+
+        assert_eq!(parse_scl(src).unwrap_err(), ParseError::new(4, "invalid pitch `0/1`"));
+    }
+
+    #[test]
+    fn non_linear_kbm() {
+        let shuffled = A440.replace("\n3\n4\n", "\n4\n3\n");
+        let e = parse_kbm(&shuffled).unwrap_err();
+        assert_eq!(e.line, 20);
+        assert_eq!(e.message, "only linear keyboard mappings are supported");
+
+        let period = A440.replace("\n12\n! Mapping.", "\n7\n! Mapping.");
+        let e = parse_kbm(&period).unwrap_err();
+        assert_eq!(e.line, 15);
+        assert_eq!(e.message, "only linear keyboard mappings are supported");
+    }
+}
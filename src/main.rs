@@ -14,8 +14,10 @@ extern crate cgmath;
 extern crate palette;
 extern crate clap;
 extern crate portmidi;
+#[cfg(feature = "synth")]
+extern crate cpal;
 
-use portmidi::{PortMidi, MidiMessage, OutputPort, Result as PmResult};
+use portmidi::PortMidi;
 
 use gfx::{Device};
 use gfx_window_glutin as gfx_glutin;
@@ -23,102 +25,94 @@ use glutin::GlContext;
 
 use cgmath::Vector2;
 
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
 use renderer::{Render, Vertex};
+use midi::{MusicBox, Play};
+use record::Recorder;
+use ui::Pointer;
 
 mod ui;
 mod layout;
 mod renderer;
+mod midi;
+mod config;
+mod font;
+mod smf;
+mod record;
+#[cfg(feature = "synth")]
+mod synth;
 
 pub type ColorFormat = gfx::format::Rgba8;
 pub type DepthFormat = gfx::format::DepthStencil;
 
-struct MusicBox {
-    port: OutputPort,
-    key: u8,
-    notes: Vec<i32>,
-}
-
-impl MusicBox {
-    fn new(port: OutputPort) -> Self {
-        MusicBox {
-            port: port,
-            key: 60,
-            notes: vec![],
-        }
-    }
-
-    fn note_on(&mut self, note: i32) -> PmResult<()> {
-        let key = self.key as i32 + note;
-
-        if key >= 0 && key <= 127 {
-            let msg = MidiMessage {
-                status: 0x90,
-                data1: key as u8,
-                data2: 64,
-            };
-
-            self.notes.push(note);
-            self.port.write_message(msg)?
-        }
-        Ok(())
-    }
-
-    fn note_off(&mut self, note: i32) -> PmResult<()> {
-        let key = self.key as i32 + note;
-
-        let msg = MidiMessage {
-            status: 0x80,
-            data1: key as u8,
-            data2: 64,
-        };
-
-        self.port.write_message(msg)
-    }
-
-    fn all_notes_off(&mut self) {
-        // Better to send ALL NOTES OFF, but there're some synths that don't understand it
-        let mut notes = vec![];
-        ::std::mem::swap(&mut notes, &mut self.notes);
-        for &n in &notes {
-            drop(self.note_off(n))
-        }
-    }
-}
-
 #[derive(Debug)]
 enum Msg {
     Resized(Vector2<f32>),
+    MouseMoved(Vector2<f32>),
     LeftPressed(Vector2<f32>),
     LeftReleased,
-    Keyboard(glutin::ElementState, glutin::VirtualKeyCode)
+    MiddlePressed(Vector2<f32>),
+    Pan(Vector2<f32>),
+    Zoom(f32, Vector2<f32>),
+    Touch(u64, glutin::TouchPhase, Vector2<f32>),
+    Keyboard(glutin::ElementState, glutin::VirtualKeyCode),
+    /// The layout with this index was changed on disk
+    Reload(usize, layout::Layout),
+    /// A note of the played file, off if the velocity is 0
+    PlayStep(i32, u8),
+    /// A note of the MIDI input by channel and key, off if the velocity is 0
+    MidiNote(u8, u8, u8),
+    /// The sustain pedal of the MIDI input
+    Sustain(bool),
 }
 
 #[derive(Debug)]
 struct Intent {
-    mouse_pos: Vector2<f32>
+    mouse_pos: Vector2<f32>,
+    panning: bool,
 }
 
 impl Intent {
     fn new() -> Self {
         Intent {
-            mouse_pos: Vector2::new(0.0, 0.0)
+            mouse_pos: Vector2::new(0.0, 0.0),
+            panning: false,
         }
     }
 
     fn intent(&mut self, ev: glutin::WindowEvent) -> Option<Msg> {
         use glutin::WindowEvent as E;
         use glutin::ElementState::*;
-        use glutin::MouseButton::{Left};
+        use glutin::MouseButton::{Left, Middle, Right};
+        use glutin::MouseScrollDelta::*;
 
         Some(match ev {
             E::Resized(w, h) => Msg::Resized(Vector2::new(w as f32, h as f32)),
             E::CursorMoved {position: (w, h), ..} => {
-                self.mouse_pos = Vector2::new(w as f32, h as f32);
-                return None
+                let pos = Vector2::new(w as f32, h as f32);
+                let by = pos - self.mouse_pos;
+                self.mouse_pos = pos;
+
+                if self.panning { Msg::Pan(by) }
+                else { Msg::MouseMoved(pos) }
             },
             E::MouseInput {state: Pressed, button:Left, ..} =>
                 Msg::LeftPressed(self.mouse_pos),
             E::MouseInput {state: Released, button:Left, ..} => Msg::LeftReleased,
+            E::MouseInput {state: Pressed, button:Middle, ..} =>
+                Msg::MiddlePressed(self.mouse_pos),
+            E::MouseInput {state, button:Right, ..} => {
+                self.panning = state == Pressed;
+                return None
+            },
+            E::MouseWheel {delta: LineDelta(_, y), ..} =>
+                Msg::Zoom(1.1f32.powf(y), self.mouse_pos),
+            E::MouseWheel {delta: PixelDelta(_, y), ..} =>
+                Msg::Zoom(1.1f32.powf(y / 20.0), self.mouse_pos),
+            E::Touch(glutin::Touch {id, phase, location: (x, y), ..}) =>
+                Msg::Touch(id, phase, Vector2::new(x as f32, y as f32)),
             E::KeyboardInput {input: glutin::KeyboardInput {
                 state: es, virtual_keycode: Some(vk), ..
             }, ..} =>
@@ -128,20 +122,178 @@ impl Intent {
     }
 }
 
+/// Messages of the MIDI input that mean something to the model
+fn midi_intent(msg: portmidi::MidiMessage) -> Option<Msg> {
+    let channel = msg.status & 0x0f;
+
+    Some(match msg.status & 0xf0 {
+        0x90 => Msg::MidiNote(channel, msg.data1, msg.data2),
+        0x80 => Msg::MidiNote(channel, msg.data1, 0),
+        0xb0 if msg.data1 == 64 => Msg::Sustain(msg.data2 >= 64),
+        _ => return None,
+    })
+}
+
+/// Notices when a file is saved
+#[derive(Debug)]
+struct Watch {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+impl Watch {
+    fn new<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        Watch {
+            modified: Watch::modified(&path),
+            path,
+            checked: Instant::now(),
+        }
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    /// Whether the file has changed since the last time, it's looked at twice a second
+    fn changed(&mut self) -> bool {
+        if self.checked.elapsed() < Duration::from_millis(500) {
+            return false
+        }
+        self.checked = Instant::now();
+
+        let modified = Watch::modified(&self.path);
+        if modified.is_some() && modified != self.modified {
+            self.modified = modified;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Notes of a MIDI file that come in time
+#[derive(Debug)]
+struct Playback {
+    events: Vec<smf::NoteEvent>,
+    next: usize,
+    start: Instant,
+}
+
+impl Playback {
+    fn new(events: Vec<smf::NoteEvent>) -> Self {
+        Playback { events, next: 0, start: Instant::now() }
+    }
+
+    /// The notes that are due since the last time
+    fn due(&mut self) -> &[smf::NoteEvent] {
+        let elapsed = self.start.elapsed();
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+
+        let from = self.next;
+        while self.next < self.events.len() && self.events[self.next].time <= elapsed {
+            self.next += 1
+        }
+
+        &self.events[from..self.next]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Note {
+    pointer: Pointer,
+    step: i32,
+    velocity: u8,
+    /// Where the hex was hit and where the pointer is now, relative to the hex center
+    start: Vector2<f32>,
+    at: Vector2<f32>,
+    /// Whether the pointer has left where it hit, there's no expression until then
+    moved: bool,
+}
+
+impl Note {
+    fn new(pointer: Pointer, step: i32, velocity: u8, start: Vector2<f32>) -> Self {
+        Note { pointer, step, velocity, start, at: start, moved: false }
+    }
+}
+
 #[derive(Debug)]
 struct Model {
     hexes: ui::Hexes,
-    notes: Vec<i32>,
+    keymap: config::Keymap,
+    velocity: config::Velocity,
+    expression: config::Expression,
+    midi_in: config::MidiIn,
+    /// Play the notes of the MIDI input, not just show them
+    thru: bool,
+    notes: Vec<Note>,
     sustain: bool,
+    /// Layouts to switch between, the one in use is `current`
+    layouts: Vec<layout::Layout>,
+    current: usize,
+    recording: bool,
 }
 
 impl Model {
-    fn new(size: Vector2<f32>, layout: layout::Layout) -> Self {
+    fn title(&self) -> String {
+        let mut title = format!("31key: {}", self.hexes.layout.name);
+        if self.hexes.transpose != 0 {
+            title += &format!(", transposed by {:+}", self.hexes.transpose)
+        }
+        if self.recording {
+            title += ", recording"
+        }
+
+        title
+    }
+
+    fn new(size: Vector2<f32>, layouts: Vec<layout::Layout>, config: config::Config, keymap: config::Keymap) -> Self {
+        let mut hexes = ui::Hexes::new(size, layouts[0].clone());
+        hexes.marked = keymap.keys.iter().map(|k| k.1).collect();
+
         Model {
-            hexes: ui::Hexes::new(size, layout),
+            hexes,
+            keymap,
+            velocity: config.velocity,
+            expression: config.expression,
+            midi_in: config.midi_in,
+            thru: false,
             notes: vec![],
             sustain: false,
+            layouts,
+            current: 0,
+            recording: false,
+        }
+    }
+}
+
+/// Put another layout in place, the held hexes may mean other steps in it
+fn switch_layout(hexes: &mut ui::Hexes, notes: &mut Vec<Note>, layout: layout::Layout) {
+    hexes.release_all();
+    notes.clear();
+    hexes.layout = layout
+}
+
+/// Shift the grid and retarget the held notes
+fn transpose(hexes: &mut ui::Hexes, notes: &mut Vec<Note>, transpose: i32) {
+    let delta = transpose - hexes.transpose;
+    hexes.transpose = transpose;
+    for note in notes.iter_mut() {
+        note.step += delta
+    }
+}
+
+/// Release the notes of the pointer, or let the sustain hold them
+fn release(hexes: &mut ui::Hexes, notes: &mut Vec<Note>, pointer: Pointer, sustain: bool) {
+    if sustain {
+        hexes.hand_over(pointer, Pointer::Sustain);
+        for note in notes.iter_mut().filter(|n| n.pointer == pointer) {
+            note.pointer = Pointer::Sustain
         }
+    } else {
+        hexes.release(pointer);
+        notes.retain(|n| n.pointer != pointer)
     }
 }
 
@@ -149,52 +301,229 @@ fn model(model: Model, msg: Msg) -> Model {
     use Msg::*;
     use glutin::ElementState::*;
     use glutin::VirtualKeyCode::*;
+    use glutin::TouchPhase;
 
-    let Model { mut hexes, mut notes, mut sustain } = model;
+    let Model {
+        mut hexes, keymap, velocity, expression, midi_in, thru,
+        mut notes, mut sustain, mut layouts, mut current, mut recording,
+    } = model;
+
+    let press = |hexes: &mut ui::Hexes, notes: &mut Vec<Note>, pointer, xy| {
+        let (step, offset) = hexes.press(pointer, xy);
+        notes.push(Note::new(pointer, step, velocity.velocity(offset), offset))
+    };
+    let drag = |hexes: &ui::Hexes, notes: &mut Vec<Note>, pointer, xy| {
+        if let Some(offset) = hexes.offset(pointer, xy) {
+            for note in notes.iter_mut().filter(|n| n.pointer == pointer) {
+                note.moved |= offset != note.start;
+                note.at = offset
+            }
+        }
+    };
 
     match msg {
         Resized(wh) =>
             hexes.size = wh,
-        LeftPressed(xy) => {
-            let note = hexes.press(xy);
-            notes.push(note)
+        MouseMoved(xy) =>
+            drag(&hexes, &mut notes, Pointer::Mouse, xy),
+        LeftPressed(xy) =>
+            press(&mut hexes, &mut notes, Pointer::Mouse, xy),
+        LeftReleased =>
+            release(&mut hexes, &mut notes, Pointer::Mouse, sustain),
+        Pan(by) =>
+            hexes.pan(by),
+        Zoom(factor, xy) =>
+            hexes.zoom(factor, xy),
+        Keyboard(Pressed, Back) =>
+            hexes.reset_view(),
+        Keyboard(Pressed, F1) =>
+            hexes.labels = hexes.labels.next(),
+        Keyboard(Pressed, F9) =>
+            recording = !recording,
+        Keyboard(Pressed, Tab) => {
+            current = (current + 1) % layouts.len();
+            switch_layout(&mut hexes, &mut notes, layouts[current].clone());
+            hexes.transpose = 0
+        },
+        MiddlePressed(xy) => {
+            // The hex becomes the new step 0
+            let t = hexes.transpose - hexes.steps_at(xy);
+            transpose(&mut hexes, &mut notes, t)
         },
-        LeftReleased if !sustain => {
-            hexes.release_all();
-            notes.clear()
+        Touch(id, TouchPhase::Started, xy) =>
+            press(&mut hexes, &mut notes, Pointer::Touch(id), xy),
+        Touch(id, TouchPhase::Moved, xy) => {
+            let pointer = Pointer::Touch(id);
+            if hexes.slid_off(pointer, xy) {
+                release(&mut hexes, &mut notes, pointer, sustain);
+                press(&mut hexes, &mut notes, pointer, xy)
+            } else {
+                drag(&hexes, &mut notes, pointer, xy)
+            }
         },
-        Keyboard(Pressed, Space) =>
+        Touch(id, _, _) =>
+            release(&mut hexes, &mut notes, Pointer::Touch(id), sustain),
+        Keyboard(Pressed, Space) | Sustain(true) =>
             sustain = true,
-        Keyboard(Released, Space) => {
+        Keyboard(Released, Space) | Sustain(false) => {
             sustain = false;
-            hexes.release_all();
-            notes.clear()
+            release(&mut hexes, &mut notes, Pointer::Sustain, sustain)
+        },
+        Keyboard(Pressed, k @ Left) | Keyboard(Pressed, k @ Right) |
+        Keyboard(Pressed, k @ Up) | Keyboard(Pressed, k @ Down) |
+        Keyboard(Pressed, k @ PageUp) | Keyboard(Pressed, k @ PageDown) => {
+            let tuning = &hexes.layout.tuning;
+            let by = match k {
+                Left => -1,
+                Right => 1,
+                Down => -tuning.fifth(),
+                Up => tuning.fifth(),
+                PageDown => -tuning.divisions,
+                _ => tuning.divisions,
+            };
+            let t = hexes.transpose + by;
+            transpose(&mut hexes, &mut notes, t)
+        },
+        Keyboard(Pressed, Home) =>
+            transpose(&mut hexes, &mut notes, 0),
+        Keyboard(Pressed, vk) => {
+            let pointer = Pointer::Key(vk);
+            // Ignore the key repeat
+            if let (Some(qr), false) = (keymap.hex(vk), hexes.holds(pointer)) {
+                let step = hexes.press_hex(pointer, qr);
+                let center = Vector2::new(0.0, 0.0);
+                notes.push(Note::new(pointer, step, velocity.velocity(center), center))
+            }
+        },
+        Keyboard(Released, vk) =>
+            release(&mut hexes, &mut notes, Pointer::Key(vk), sustain),
+        PlayStep(step, 0) =>
+            release(&mut hexes, &mut notes, Pointer::Playback(step), false),
+        PlayStep(step, velocity) => {
+            let pointer = Pointer::Playback(step);
+            // A note on that comes again before the note off
+            release(&mut hexes, &mut notes, pointer, false);
+            if let Some(qr) = hexes.hex_for(step) {
+                hexes.press_hex(pointer, qr);
+            }
+            let center = Vector2::new(0.0, 0.0);
+            notes.push(Note::new(pointer, step, velocity, center))
+        },
+        MidiNote(channel, key, 0) =>
+            release(&mut hexes, &mut notes, Pointer::Midi(channel, key), sustain),
+        MidiNote(channel, key, velocity) => {
+            let pointer = Pointer::Midi(channel, key);
+            release(&mut hexes, &mut notes, pointer, false);
+
+            let step = key as i32 - 60 + hexes.transpose;
+            let step = match midi_in.hex(channel, key).or_else(|| hexes.hex_for(step)) {
+                Some(qr) => hexes.press_hex(pointer, qr),
+                None => step,
+            };
+            // Otherwise the controller plays by itself and the grid only shows it
+            if thru {
+                let center = Vector2::new(0.0, 0.0);
+                notes.push(Note::new(pointer, step, velocity, center))
+            }
+        },
+        Reload(i, layout) => {
+            if i == current {
+                switch_layout(&mut hexes, &mut notes, layout.clone())
+            }
+            layouts[i] = layout
         },
-        _ => (),
     };
 
-    Model { hexes, notes, sustain }
+    Model {
+        hexes, keymap, velocity, expression, midi_in, thru,
+        notes, sustain, layouts, current, recording,
+    }
 }
 
 fn draw(model: &Model, renderer: &mut renderer::Renderer) {
     model.hexes.draw(renderer)
 }
 
-fn update_midi(model: &Model, the_box: &mut MusicBox) {
-    let mn = model.notes.len();
-    let bn = the_box.notes.len();
-    if mn > bn {
-        for n in &model.notes[bn..] {
-            drop(the_box.note_on(*n))
+/// Bring what's playing in line with the model
+fn update_player<P: Play>(model: &Model, player: &mut P) {
+    let released: Vec<i32> = player.notes().iter()
+        .cloned()
+        .filter(|&n| !model.notes.iter().any(|m| m.step == n))
+        .collect();
+    for n in released {
+        player.note_off(n)
+    }
+
+    if *player.tuning() != model.hexes.layout.tuning {
+        player.retune(model.hexes.layout.tuning.clone())
+    }
+
+    for note in &model.notes {
+        if !player.notes().contains(&note.step) {
+            player.note_on(note.step, note.velocity)
         }
     }
-    else if mn == 0 {
-        the_box.all_notes_off()
+
+    let expression = model.expression;
+    for note in model.notes.iter().filter(|n| n.moved) {
+        let bend = expression.bend * (note.at.x - note.start.x).max(-1.0).min(1.0);
+        let timbre =
+            if expression.timbre { Some(((note.at.y - note.start.y).max(-1.0).min(1.0) + 1.0) / 2.0) }
+            else { None };
+
+        player.expression(note.step, bend, timbre)
     }
 }
 
-fn main() {
-    let matches = clap::App::new("31key")
+/// Play a MIDI file into a WAV file, without any window or sound card
+#[cfg(feature = "synth")]
+fn render(matches: &clap::ArgMatches, preset: synth::Preset, tuning: &layout::Tuning) {
+    let input = matches.value_of("input").unwrap();
+    let output = matches.value_of("output").unwrap();
+    let rate = match matches.value_of("rate").map(str::parse::<u32>) {
+        Some(Ok(rate)) if rate > 0 => rate,
+        _ => {
+            eprintln!("The sample rate must be a positive number");
+            std::process::exit(1)
+        },
+    };
+
+    let src = std::fs::read(input).unwrap_or_else(|e| fail(input, e));
+    let smf = smf::parse(&src).unwrap_or_else(|e| fail(input, e));
+    let samples = synth::render_notes(&smf.note_events(), preset, tuning.clone(), rate);
+
+    let file = std::fs::File::create(output).unwrap_or_else(|e| fail(output, e));
+    synth::write_wav(std::io::BufWriter::new(file), rate, &samples).unwrap_or_else(|e| fail(output, e))
+}
+
+/// Put the setup into the take without sending it, the synth has it already
+fn record_setup<O: midi::Output>(the_box: &mut MusicBox<Recorder<O>>) {
+    let mut setup = vec![];
+    drop(the_box.write_setup(&mut setup));
+    the_box.output_mut().add(setup)
+}
+
+/// Write the take to a new file in the current directory
+fn save_recording(take: &smf::Smf) {
+    let secs = SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let path = format!("31key-{}.mid", secs);
+
+    match std::fs::File::create(&path).and_then(|f| smf::write(std::io::BufWriter::new(f), take)) {
+        Ok(()) => println!("Recorded {}", path),
+        Err(e) => eprintln!("{}: {}", path, e),
+    }
+}
+
+/// Report a bad input file and exit
+fn fail<E: std::fmt::Display>(path: &str, e: E) -> ! {
+    eprintln!("{}: {}", path, e);
+    std::process::exit(1)
+}
+
+fn app() -> clap::App<'static, 'static> {
+    let presets: Vec<_> = layout::Preset::all().iter().map(|p| p.name()).collect();
+
+    let app = clap::App::new("31key")
         .version(env!("CARGO_PKG_VERSION"))
         .about("A mictotonal keyboard")
         .arg(
@@ -207,34 +536,309 @@ fn main() {
             clap::Arg::with_name("ron")
             .long("ron")
             .takes_value(true)
-            .help("Load the layout from ron")
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("file|dir")
+            .help("Load the layout from ron, or all the layouts in a directory, Tab switches between them")
         )
-        .get_matches();
+        .arg(
+            clap::Arg::with_name("config")
+            .long("config")
+            .takes_value(true)
+            .help("Load the settings from ron")
+        )
+        .arg(
+            clap::Arg::with_name("scl")
+            .long("scl")
+            .takes_value(true)
+            .conflicts_with("ron")
+            .help("Load the tuning from a Scala scale file")
+        )
+        .arg(
+            clap::Arg::with_name("kbm")
+            .long("kbm")
+            .takes_value(true)
+            .requires("scl")
+            .help("Map the Scala scale with a keyboard mapping file")
+        )
+        .arg(
+            clap::Arg::with_name("layout")
+            .long("layout")
+            .takes_value(true)
+            .value_name("preset")
+            .possible_values(&presets)
+            .help("Rearrange the layout like a classic isomorphic keyboard")
+        )
+        .arg(
+            clap::Arg::with_name("channel")
+            .long("channel")
+            .default_value("1")
+            .help("MIDI channel of the notes, or of step 0 with --channel-block. Not for MPE")
+        )
+        .arg(
+            clap::Arg::with_name("channel-block")
+            .long("channel-block")
+            .takes_value(true)
+            .value_name("steps|period")
+            .help("Send every block of steps to a channel of its own, keys start from 0 in every block")
+        )
+        .arg(
+            clap::Arg::with_name("list-ports")
+            .long("list-ports")
+            .help("Print the available MIDI outputs and inputs and exit")
+        )
+        .arg(
+            clap::Arg::with_name("port")
+            .long("port")
+            .takes_value(true)
+            .value_name("name|id")
+            .help("Send MIDI to the output with this id or name")
+        )
+        .arg(
+            clap::Arg::with_name("mode")
+            .long("mode")
+            .possible_values(&["plain", "mpe", "mts"])
+            .default_value("plain")
+            .help("MIDI output mode")
+        )
+        .arg(
+            clap::Arg::with_name("input")
+            .long("input")
+            .takes_value(true)
+            .value_name("name|id")
+            .help("Show the notes of the MIDI input with this id or name on the grid")
+        )
+        .arg(
+            clap::Arg::with_name("thru")
+            .long("thru")
+            .requires("input")
+            .help("Play the notes of the MIDI input in the tuning of the layout")
+        )
+        .arg(
+            clap::Arg::with_name("play")
+            .long("play")
+            .takes_value(true)
+            .value_name("file")
+            .help("Play a MIDI file on the grid, key 60 is step 0 unless it's a recording of 31key")
+        );
+    #[cfg(feature = "synth")]
+    let synths: Vec<_> = synth::Preset::all().iter().map(|p| p.name()).collect();
+    #[cfg(feature = "synth")]
+    let app = app.arg(
+        clap::Arg::with_name("synth")
+        .long("synth")
+        .takes_value(true)
+        .value_name("preset")
+        .possible_values(&synths)
+        .help("Play with the builtin synth, MIDI is sent only if there's a --port too")
+    ).subcommand(
+        clap::SubCommand::with_name("render")
+        .about("Render a MIDI file with the layout's tuning and the synth to a WAV file, key 60 is step 0")
+        .arg(
+            clap::Arg::with_name("input")
+            .required(true)
+            .help("MIDI file to play")
+        )
+        .arg(
+            clap::Arg::with_name("output")
+            .required(true)
+            .help("WAV file to write")
+        )
+        .arg(
+            clap::Arg::with_name("rate")
+            .long("rate")
+            .default_value("44100")
+            .help("Sample rate")
+        )
+    );
+
+    app
+}
+
+fn main() {
+    let matches = app().get_matches();
+
+    // Every layout comes with the file to watch if there's one
+    let mut layouts: Vec<(layout::Layout, Option<PathBuf>)> = vec![];
+    if let Some(paths) = matches.values_of("ron") {
+        for path in paths {
+            for file in layout::layout_files(path).unwrap_or_else(|e| fail(path, e)) {
+                let layout = layout::load(&file)
+                    .unwrap_or_else(|e| fail(&file.display().to_string(), e));
+                layouts.push((layout, Some(file)))
+            }
+        }
+    } else if let Some(path) = matches.value_of("scl") {
+        let read = |path| layout::read(path).unwrap_or_else(|e| fail(path, e));
+
+        let scale = layout::parse_scl(&read(path)).unwrap_or_else(|e| fail(path, e));
+        let kbm = matches.value_of("kbm")
+            .map(|path| layout::parse_kbm(&read(path)).unwrap_or_else(|e| fail(path, e)));
+
+        let mut layout = layout::scala_layout(&scale, kbm.as_ref())
+            .unwrap_or_else(|e| fail(matches.value_of("kbm").unwrap_or(path), e));
+        if layout.name.is_empty() {
+            layout.name = path.into()
+        }
+        layouts.push((layout, None))
+    } else {
+        match matches.value_of("edo").map(str::parse) {
+            Some(Ok(n)) if n > 0 => layouts.push((layout::edo_layout(n), None)),
+            _ => {
+                eprintln!("Unsupported EDO");
+                std::process::exit(1)
+            },
+        }
+    }
 
-    let layout =
-        if let Some(path) = matches.value_of("ron") {
-            use std::io::Read;
-            let mut file = std::fs::File::open(path).expect("file not found");
+    // The builtin layouts follow the chosen ones
+    for &n in &[12, 31, 53] {
+        let layout = layout::edo_layout(n);
+        if !layouts.iter().any(|l| l.0.name == layout.name) {
+            layouts.push((layout, None))
+        }
+    }
+
+    let preset = matches.value_of("layout").and_then(layout::Preset::from_name);
+    let with_preset = |layout: layout::Layout| match preset {
+        Some(preset) => {
+            let layout = layout.with_preset(preset);
+            let stride = layout.step_stride();
+            if stride > 1 {
+                eprintln!("{}: the preset skips steps, only multiples of {} are on the grid", layout.name, stride)
+            }
+            layout
+        },
+        None => layout,
+    };
+    let mut watches: Vec<(usize, Watch)> = layouts.iter()
+        .enumerate()
+        .filter_map(|(i, l)| l.1.as_ref().map(|path| (i, Watch::new(path.clone()))))
+        .collect();
+    let layouts: Vec<_> = layouts.into_iter().map(|l| with_preset(l.0)).collect();
+
+    #[cfg(feature = "synth")]
+    {
+        if let Some(render_matches) = matches.subcommand_matches("render") {
+            let preset = matches.value_of("synth")
+                .and_then(synth::Preset::from_name)
+                .unwrap_or(synth::Preset::Piano);
+            render(render_matches, preset, &layouts[0].tuning);
+            return
+        }
+    }
 
-            let mut ron = String::new();
-            file.read_to_string(&mut ron)
-                .expect("something went wrong reading the file");
+    let playback = matches.value_of("play").map(|path| {
+        let src = std::fs::read(path).unwrap_or_else(|e| fail(path, e));
+        smf::parse(&src).unwrap_or_else(|e| fail(path, e)).note_events()
+    });
 
-            let layout: layout::LayoutConfig =
-                ron::de::from_str(&ron).unwrap();
+    let midi = PortMidi::new().expect("failed to initialize PortMidi");
 
-            layout.into()
+    if matches.is_present("list-ports") {
+        println!("Outputs:");
+        for device in midi::output_devices(&midi).expect("failed to list MIDI devices") {
+            println!("{}: {}", device.id(), device.name())
+        }
+        println!("Inputs:");
+        for device in midi::input_devices(&midi).expect("failed to list MIDI devices") {
+            println!("{}: {}", device.id(), device.name())
+        }
+        return
+    }
+
+    let config: config::Config =
+        if let Some(path) = matches.value_of("config") {
+            let ron = layout::read(path).unwrap_or_else(|e| fail(path, e));
+            ron::de::from_str(&ron).unwrap_or_else(|e| fail(path, e))
         } else {
-            match matches.value_of("edo") {
-                Some("31") => layout::edo31_layout(),
-                Some("12") => layout::edo12_layout(),
-                Some("53") => layout::edo53_layout(),
-                _ => {
-                    eprintln!("Unsupported EDO");
-                    return
+            Default::default()
+        };
+
+    let keymap = match config::Keymap::new(&config.keys) {
+        Ok(keymap) => keymap,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        },
+    };
+
+    let mode = match matches.value_of("mode") {
+        Some("mpe") => midi::Mode::Mpe,
+        Some("mts") => midi::Mode::Mts,
+        _ => midi::Mode::Plain,
+    };
+
+    let channel = match matches.value_of("channel").map(str::parse::<u8>) {
+        Some(Ok(ch)) if ch >= 1 && ch <= 16 => ch - 1,
+        _ => {
+            eprintln!("The channel must be from 1 to 16");
+            std::process::exit(1)
+        },
+    };
+    if mode == midi::Mode::Mpe && channel != 0 {
+        eprintln!("--channel doesn't work in the MPE mode, it always uses channels 1 to 16");
+        std::process::exit(1)
+    }
+    let channels = match matches.value_of("channel-block") {
+        None => midi::Channels::Single(channel),
+        Some(_) if mode != midi::Mode::Plain => {
+            eprintln!("--channel-block works only in the plain mode");
+            std::process::exit(1)
+        },
+        Some("period") =>
+            midi::Channels::Periods { home: channel },
+        Some(size) => match size.parse() {
+            Ok(size) if size > 0 && size <= 128 =>
+                midi::Channels::Blocks { size, home: channel },
+            _ => {
+                eprintln!("The channel block must be from 1 to 128 steps");
+                std::process::exit(1)
+            },
+        },
+    };
+
+    #[cfg(feature = "synth")]
+    let synth = matches.value_of("synth").and_then(synth::Preset::from_name).map(|preset| {
+        synth::play(preset, layouts[0].tuning.clone()).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        })
+    });
+
+    let port =
+        if matches.is_present("synth") && !matches.is_present("port") {
+            None
+        } else {
+            let port = match matches.value_of("port") {
+                Some(query) => midi::find_output(&midi, query)
+                    .and_then(|d| midi.output_port(d, 1024).map_err(|e| e.to_string())),
+                None => midi.default_output_port(1024)
+                    .map_err(|_| "there's no default MIDI output, choose one with --port".to_string()),
+            };
+            match port {
+                Ok(port) => Some(port),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1)
                 },
             }
         };
+    let input = match matches.value_of("input") {
+        Some(query) => match midi::find_input(&midi, query)
+            .and_then(|d| midi.input_port(d, 1024).map_err(|e| e.to_string())) {
+            Ok(port) => Some(port),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            },
+        },
+        None => None,
+    };
+
+    let mut the_box = MusicBox::new(Recorder::new(port), mode, layouts[0].tuning.clone());
+    the_box.channels = channels;
+    the_box.setup().expect("failed to set up the MIDI output");
 
     let mut events_loop = glutin::EventsLoop::new();
     let builder = glutin::WindowBuilder::new()
@@ -249,13 +853,21 @@ fn main() {
     let encoder: gfx::Encoder<_, _> = factory.create_command_buffer().into();
     let mut renderer = renderer::Renderer::new(factory, encoder, main_color);
 
-    let midi = PortMidi::new().unwrap();
-    let port = midi.default_output_port(1024).unwrap();
-    let mut the_box = MusicBox::new(port);
-
     let mut mailbox = vec![];
     let mut intent = Intent::new();
-    let mut the_model = Model::new(Vector2::new(960.0, 600.0), layout);
+    let mut the_model = Model::new(Vector2::new(960.0, 600.0), layouts, config, keymap);
+
+    the_model.thru = matches.is_present("thru");
+
+    let mut title = the_model.title();
+    window.set_title(&title);
+
+    // Recordings name the notes the way the layout in use does
+    let mut named = the_model.hexes.layout.clone();
+    the_box.output_mut().names = named.note_names();
+
+    // The file starts playing once the window is there
+    let mut playback = playback.map(Playback::new);
 
     let mut running = true;
     let mut needs_update = true;
@@ -280,13 +892,73 @@ fn main() {
             }
         });
 
+        for &mut (i, ref mut watch) in &mut watches {
+            if watch.changed() {
+                match layout::load(&watch.path) {
+                    Ok(layout) => {
+                        mailbox.push(Msg::Reload(i, with_preset(layout)));
+                        needs_update = true
+                    },
+                    // Keep playing the old one
+                    Err(e) => eprintln!("{}: {}", watch.path.display(), e),
+                }
+            }
+        }
+
+        if let Some(ref input) = input {
+            if let Ok(Some(events)) = input.read_n(1024) {
+                for event in events {
+                    if let Some(msg) = midi_intent(event.message) {
+                        mailbox.push(msg);
+                        needs_update = true
+                    }
+                }
+            }
+        }
+
+        if let Some(ref mut playback) = playback {
+            for note in playback.due() {
+                mailbox.push(Msg::PlayStep(note.step, note.velocity));
+                needs_update = true
+            }
+        }
+
         if needs_update {
             for m in mailbox.drain(0..) {
                 the_model = model(the_model, m)
             }
 
+            if the_model.title() != title {
+                title = the_model.title();
+                window.set_title(&title)
+            }
+            if the_model.hexes.layout != named {
+                named = the_model.hexes.layout.clone();
+                the_box.output_mut().names = named.note_names()
+            }
+
             draw(&the_model, &mut renderer);
-            update_midi(&the_model, &mut the_box);
+            if the_model.recording != the_box.output_mut().recording() {
+                if the_model.recording {
+                    the_box.output_mut().start();
+                    record_setup(&mut the_box)
+                } else if let Some(take) = record::stop(&mut the_box) {
+                    save_recording(&take)
+                }
+            }
+
+            let retuned = *the_box.tuning() != the_model.hexes.layout.tuning;
+            update_player(&the_model, &mut the_box);
+            // The synth gets the new tuning in the MTS mode only, but the take needs it anyway
+            if retuned && mode == midi::Mode::Plain && the_box.output_mut().recording() {
+                record_setup(&mut the_box)
+            }
+            #[cfg(feature = "synth")]
+            {
+                if let Some(ref synth) = synth {
+                    update_player(&the_model, &mut *synth.lock().unwrap())
+                }
+            }
             renderer.draw(&mut device);
             window.swap_buffers().unwrap();
             device.cleanup();
@@ -294,7 +966,44 @@ fn main() {
             needs_update = false;
         }
 
-        let dt = ::std::time::Duration::from_millis(10);
+        let dt = Duration::from_millis(10);
         ::std::thread::sleep(dt);
     }
+
+    // Closing the window ends the recording too
+    if let Some(take) = record::stop(&mut the_box) {
+        save_recording(&take)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_preset() {
+        let matches = app().get_matches_from_safe(vec!["31key", "--layout", "wicki-hayden"]).unwrap();
+
+        assert_eq!(matches.value_of("layout"), Some("wicki-hayden"));
+        assert_eq!(
+            matches.value_of("layout").and_then(layout::Preset::from_name),
+            Some(layout::Preset::WickiHayden)
+        );
+    }
+
+    #[test]
+    fn unknown_layout_preset() {
+        assert!(app().get_matches_from_safe(vec!["31key", "--layout", "piano"]).is_err());
+    }
+
+    #[cfg(feature = "synth")]
+    #[test]
+    fn synth_preset() {
+        let matches = app().get_matches_from_safe(vec!["31key", "--synth", "saw"]).unwrap();
+
+        assert_eq!(
+            matches.value_of("synth").and_then(synth::Preset::from_name),
+            Some(synth::Preset::Saw)
+        );
+    }
 }
@@ -0,0 +1,672 @@
+use portmidi::{PortMidi, DeviceInfo, MidiMessage, OutputPort, Result as PmResult};
+
+use layout::Tuning;
+
+// MPE lower zone: channel 1 is the manager, channels 2..16 are members
+const MANAGER: u8 = 0;
+const MEMBERS: u8 = 15;
+const BEND_RANGE: f32 = 48.0;
+
+// MIDI Tuning Standard
+const ALL_DEVICES: u8 = 0x7f;
+const TUNING_PROGRAM: u8 = 0;
+const TUNING_NAME: &[u8; 16] = b"31key           ";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Plain,
+    Mpe,
+    Mts,
+}
+
+/// Where the notes go outside of MPE
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channels {
+    /// Every note on one channel, key 60 is step 0
+    Single(u8),
+    /// Every `size` steps on a channel of their own, numbered from 0 in it.
+    /// Step 0 starts the block on `home`
+    Blocks { size: i32, home: u8 },
+    /// Blocks as large as the period of the tuning in use
+    Periods { home: u8 },
+}
+
+/// Anything that plays the steps of a tuning
+pub trait Play {
+    /// Notes that are on
+    fn notes(&self) -> &[i32];
+    fn tuning(&self) -> &Tuning;
+    fn retune(&mut self, tuning: Tuning);
+    fn note_on(&mut self, note: i32, velocity: u8);
+    fn note_off(&mut self, note: i32);
+    /// Bend in cents and timbre from 0 to 1 of a note that is on
+    fn expression(&mut self, note: i32, bend: f32, timbre: Option<f32>);
+}
+
+/// Anything MusicBox can write to, so the output can be captured
+pub trait Output {
+    fn write_message(&mut self, msg: MidiMessage) -> PmResult<()>;
+    fn write_sysex(&mut self, msg: &[u8]) -> PmResult<()>;
+
+    /// The step of the next note on, for whatever keeps the output
+    fn note_step(&mut self, _step: i32) {}
+}
+
+/// Goes nowhere, when there's only the builtin synth
+impl<O: Output> Output for Option<O> {
+    fn write_message(&mut self, msg: MidiMessage) -> PmResult<()> {
+        match *self {
+            Some(ref mut out) => out.write_message(msg),
+            None => Ok(()),
+        }
+    }
+
+    fn write_sysex(&mut self, msg: &[u8]) -> PmResult<()> {
+        match *self {
+            Some(ref mut out) => out.write_sysex(msg),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Output for OutputPort {
+    fn write_message(&mut self, msg: MidiMessage) -> PmResult<()> {
+        OutputPort::write_message(self, msg)
+    }
+
+    fn write_sysex(&mut self, msg: &[u8]) -> PmResult<()> {
+        OutputPort::write_sysex(self, 0, msg)
+    }
+}
+
+pub fn output_devices(midi: &PortMidi) -> PmResult<Vec<DeviceInfo>> {
+    Ok(midi.devices()?.into_iter().filter(|d| d.is_output()).collect())
+}
+
+pub fn input_devices(midi: &PortMidi) -> PmResult<Vec<DeviceInfo>> {
+    Ok(midi.devices()?.into_iter().filter(|d| d.is_input()).collect())
+}
+
+/// Find an output device by its id or by a part of its name
+pub fn find_output(midi: &PortMidi, query: &str) -> Result<DeviceInfo, String> {
+    let devices = output_devices(midi).map_err(|e| format!("can't list MIDI devices: {}", e))?;
+    find_device(devices, "output", query)
+}
+
+/// Find an input device by its id or by a part of its name
+pub fn find_input(midi: &PortMidi, query: &str) -> Result<DeviceInfo, String> {
+    let devices = input_devices(midi).map_err(|e| format!("can't list MIDI devices: {}", e))?;
+    find_device(devices, "input", query)
+}
+
+fn find_device(devices: Vec<DeviceInfo>, kind: &str, query: &str) -> Result<DeviceInfo, String> {
+    if let Ok(id) = query.parse() {
+        return devices.into_iter()
+            .find(|d| d.id() == id)
+            .ok_or_else(|| format!("there's no MIDI {} with id {}", kind, id))
+    }
+
+    let needle = query.to_lowercase();
+    let mut found: Vec<_> = devices.into_iter()
+        .filter(|d| d.name().to_lowercase().contains(&needle))
+        .collect();
+
+    match found.len() {
+        0 => Err(format!("no MIDI {} matches \"{}\", see --list-ports", kind, query)),
+        1 => Ok(found.remove(0)),
+        _ => {
+            let names: Vec<_> = found.iter().map(|d| format!("\"{}\"", d.name())).collect();
+            Err(format!("\"{}\" matches several MIDI {}s: {}", query, kind, names.join(", ")))
+        },
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Voice {
+    note: i32,
+    channel: u8,
+    key: u8,
+    bend: u16,
+}
+
+/// 14-bit pitch bend value of an offset in semitones
+fn pitch_bend(semitones: f32) -> u16 {
+    let bend = 8192.0 + semitones / BEND_RANGE * 8192.0;
+    bend.round().max(0.0).min(16383.0) as u16
+}
+
+pub struct MusicBox<O = OutputPort> {
+    port: O,
+    mode: Mode,
+    pub key: u8,
+    pub channels: Channels,
+    tuning: Tuning,
+    pub notes: Vec<i32>,
+    /// Last sent pitch bend and timbre of the notes
+    expressions: Vec<(i32, u16, Option<u8>)>,
+    voices: Vec<Voice>,
+    free_channels: Vec<u8>,
+}
+
+impl<O: Output> MusicBox<O> {
+    pub fn new(port: O, mode: Mode, tuning: Tuning) -> Self {
+        MusicBox {
+            port: port,
+            mode,
+            key: 60,
+            channels: Channels::Single(0),
+            tuning,
+            notes: vec![],
+            expressions: vec![],
+            voices: vec![],
+            free_channels: (MANAGER + 1..MANAGER + 1 + MEMBERS).collect(),
+        }
+    }
+
+    pub fn output_mut(&mut self) -> &mut O {
+        &mut self.port
+    }
+
+    /// Send everything the synth needs to know before the first note
+    pub fn setup(&mut self) -> PmResult<()> {
+        match self.mode {
+            Mode::Plain => Ok(()),
+            Mode::Mpe => mpe_setup(&mut self.port),
+            Mode::Mts => {
+                let dump = self.bulk_dump();
+                self.port.write_sysex(&dump)
+            },
+        }
+    }
+
+    /// What a recording starts with to play in tune elsewhere: the setup, and the tuning in
+    /// the plain mode too. Blocks of channels don't fit one tuning table, so they go without
+    pub fn write_setup<P: Output>(&self, out: &mut P) -> PmResult<()> {
+        match (self.mode, self.channels) {
+            (Mode::Mpe, _) => mpe_setup(out),
+            (Mode::Plain, Channels::Single(_)) | (Mode::Mts, _) => out.write_sysex(&self.bulk_dump()),
+            (Mode::Plain, _) => Ok(()),
+        }
+    }
+
+    /// Note offs of the sounding notes, for a recording that ends while they're held
+    pub fn write_notes_off<P: Output>(&self, out: &mut P) -> PmResult<()> {
+        for &note in &self.notes {
+            let key = match self.mode {
+                // Stolen voices are silent already
+                Mode::Mpe => self.voices.iter().find(|v| v.note == note).map(|v| (v.channel, v.key)),
+                Mode::Plain | Mode::Mts => self.plain_key(note),
+            };
+
+            if let Some((channel, key)) = key {
+                out.write_message(MidiMessage {
+                    status: 0x80 | channel,
+                    data1: key,
+                    data2: 64,
+                })?
+            }
+        }
+        Ok(())
+    }
+
+    /// Change the tuning, retuning the synth in place if it supports it
+    pub fn retune(&mut self, tuning: Tuning) -> PmResult<()> {
+        self.tuning = tuning;
+        if self.mode != Mode::Mts {
+            return Ok(())
+        }
+
+        // A single message can carry at most 127 notes
+        let keys: Vec<u8> = (0..128).collect();
+        for chunk in keys.chunks(64) {
+            let msg = self.note_changes(chunk);
+            self.port.write_sysex(&msg)?
+        }
+        Ok(())
+    }
+
+    /// Start the note. Notes that the synth can't play are still held, but silent
+    pub fn note_on(&mut self, note: i32, velocity: u8) -> PmResult<()> {
+        if self.notes.contains(&note) {
+            return Ok(())
+        }
+        self.notes.push(note);
+        self.port.note_step(note);
+
+        match self.mode {
+            Mode::Plain | Mode::Mts => self.plain_note_on(note, velocity),
+            Mode::Mpe => self.mpe_note_on(note, velocity),
+        }
+    }
+
+    pub fn note_off(&mut self, note: i32) -> PmResult<()> {
+        self.notes.retain(|&n| n != note);
+        self.expressions.retain(|e| e.0 != note);
+
+        match self.mode {
+            Mode::Plain | Mode::Mts => self.plain_note_off(note),
+            Mode::Mpe => self.mpe_note_off(note),
+        }
+    }
+
+    /// Bend a held note by `bend` cents and set its timbre, from 0 to 1.
+    /// Only the changes are sent
+    pub fn expression(&mut self, note: i32, bend: f32, timbre: Option<f32>) -> PmResult<()> {
+        if !self.notes.contains(&note) {
+            return Ok(())
+        }
+
+        // Voices exist only in MPE mode
+        let voice = self.voices.iter().find(|v| v.note == note).cloned();
+        let bend = match voice {
+            Some(v) => pitch_bend(self.tuning.pitch(note) + bend / 100.0 - v.key as f32),
+            None => 8192,
+        };
+        let timbre = timbre.map(|t| (t * 127.0).round().max(0.0).min(127.0) as u8);
+
+        let i = match self.expressions.iter().position(|e| e.0 == note) {
+            Some(i) => i,
+            None => {
+                let start = voice.map(|v| v.bend).unwrap_or(8192);
+                self.expressions.push((note, start, None));
+                self.expressions.len() - 1
+            },
+        };
+        let (_, last_bend, last_timbre) = self.expressions[i];
+        self.expressions[i] = (note, bend, timbre);
+
+        let timbre = if timbre != last_timbre { timbre } else { None };
+        match (voice, timbre) {
+            (Some(v), _) => {
+                if bend != last_bend {
+                    self.port.write_message(MidiMessage {
+                        status: 0xE0 | v.channel,
+                        data1: (bend & 0x7f) as u8,
+                        data2: (bend >> 7) as u8,
+                    })?
+                }
+                if let Some(t) = timbre {
+                    self.port.write_message(MidiMessage {
+                        status: 0xB0 | v.channel,
+                        data1: 74,
+                        data2: t,
+                    })?
+                }
+                Ok(())
+            },
+            (None, Some(t)) if self.mode != Mode::Mpe => match self.plain_key(note) {
+                Some((channel, key)) => self.port.write_message(MidiMessage {
+                    status: 0xA0 | channel,
+                    data1: key,
+                    data2: t,
+                }),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    pub fn all_notes_off(&mut self) {
+        // Better to send ALL NOTES OFF, but there're some synths that don't understand it
+        let mut notes = vec![];
+        ::std::mem::swap(&mut notes, &mut self.notes);
+        for &n in &notes {
+            drop(self.note_off(n))
+        }
+    }
+
+    /// Channel and key of the note
+    fn plain_key(&self, note: i32) -> Option<(u8, u8)> {
+        let block = |size: i32, home: u8| match note % size {
+            d if d >= 0 => (home as i32 + note / size, d),
+            d => (home as i32 + note / size - 1, size + d),
+        };
+        let (channel, key) = match self.channels {
+            Channels::Single(channel) =>
+                (channel as i32, self.key as i32 + note),
+            Channels::Blocks { size, home } => block(size, home),
+            Channels::Periods { home } => block(self.tuning.divisions, home),
+        };
+
+        if channel >= 0 && channel <= 15 && key >= 0 && key <= 127 {
+            Some((channel as u8, key as u8))
+        } else {
+            None
+        }
+    }
+
+    fn plain_note_on(&mut self, note: i32, velocity: u8) -> PmResult<()> {
+        if let Some((channel, key)) = self.plain_key(note) {
+            let msg = MidiMessage {
+                status: 0x90 | channel,
+                data1: key,
+                data2: velocity,
+            };
+
+            self.port.write_message(msg)?
+        }
+        Ok(())
+    }
+
+    fn plain_note_off(&mut self, note: i32) -> PmResult<()> {
+        if let Some((channel, key)) = self.plain_key(note) {
+            let msg = MidiMessage {
+                status: 0x80 | channel,
+                data1: key,
+                data2: 64,
+            };
+
+            self.port.write_message(msg)?
+        }
+        Ok(())
+    }
+
+    fn mpe_note_on(&mut self, note: i32, velocity: u8) -> PmResult<()> {
+        let (key, bend) = match self.bent_key(note) {
+            Some(kb) => kb,
+            None => return Ok(()),
+        };
+
+        if self.free_channels.is_empty() {
+            // Every member channel is busy, silence the oldest voice
+            let oldest = self.voices[0].note;
+            self.mpe_note_off(oldest)?
+        }
+        let channel = self.free_channels.remove(0);
+
+        self.voices.push(Voice { note, channel, key, bend });
+
+        self.port.write_message(MidiMessage {
+            status: 0xE0 | channel,
+            data1: (bend & 0x7f) as u8,
+            data2: (bend >> 7) as u8,
+        })?;
+        self.port.write_message(MidiMessage {
+            status: 0x90 | channel,
+            data1: key,
+            data2: velocity,
+        })
+    }
+
+    fn mpe_note_off(&mut self, note: i32) -> PmResult<()> {
+        let i = match self.voices.iter().position(|v| v.note == note) {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        let Voice { channel, key, .. } = self.voices.remove(i);
+        self.free_channels.push(channel);
+
+        self.port.write_message(MidiMessage {
+            status: 0x80 | channel,
+            data1: key,
+            data2: 64,
+        })
+    }
+
+    /// The nearest 12-TET key and the 14-bit pitch bend that gets from it to the note
+    fn bent_key(&self, note: i32) -> Option<(u8, u16)> {
+        let pitch = self.tuning.pitch(note);
+        let key = pitch.round();
+        if key < 0.0 || key > 127.0 {
+            return None
+        }
+
+        Some((key as u8, pitch_bend(pitch - key)))
+    }
+
+    /// MTS frequency data of a key: the semitone below and a 14-bit fraction of a semitone
+    fn key_frequency(&self, key: u8) -> [u8; 3] {
+        let note = key as i32 - self.key as i32;
+        let pitch = self.tuning.pitch(note);
+        if pitch < 0.0 || pitch >= 128.0 {
+            // "No change"
+            return [0x7f, 0x7f, 0x7f]
+        }
+
+        let semitone = pitch.floor();
+        let fraction = ((pitch - semitone) * 16384.0).round() as u16;
+        // Rounding may carry into the next semitone
+        let (semitone, fraction) =
+            if fraction >= 16384 { (semitone + 1.0, 0) }
+            else { (semitone, fraction) };
+        if semitone > 127.0 {
+            return [0x7f, 0x7f, 0x7f]
+        }
+
+        [semitone as u8, (fraction >> 7) as u8, (fraction & 0x7f) as u8]
+    }
+
+    /// Non-real-time bulk tuning dump of all 128 keys
+    fn bulk_dump(&self) -> Vec<u8> {
+        let mut msg = vec![0xf0, 0x7e, ALL_DEVICES, 0x08, 0x01, TUNING_PROGRAM];
+        msg.extend(TUNING_NAME.iter());
+        for key in 0..128 {
+            msg.extend(self.key_frequency(key).iter())
+        }
+
+        let checksum = msg[1..].iter().fold(0, |a, b| a ^ b);
+        msg.push(checksum & 0x7f);
+        msg.push(0xf7);
+
+        msg
+    }
+
+    /// Real-time single note tuning change of the given keys
+    fn note_changes(&self, keys: &[u8]) -> Vec<u8> {
+        let mut msg = vec![0xf0, 0x7f, ALL_DEVICES, 0x08, 0x02, TUNING_PROGRAM, keys.len() as u8];
+        for &key in keys {
+            msg.push(key);
+            msg.extend(self.key_frequency(key).iter())
+        }
+        msg.push(0xf7);
+
+        msg
+    }
+}
+
+fn rpn<P: Output>(out: &mut P, channel: u8, param: u8, value: u8) -> PmResult<()> {
+    let cc = |data1, data2| MidiMessage { status: 0xB0 | channel, data1, data2 };
+
+    out.write_message(cc(101, 0))?;
+    out.write_message(cc(100, param))?;
+    out.write_message(cc(6, value))?;
+    out.write_message(cc(38, 0))?;
+    // Null RPN, so stray data entry messages don't change anything
+    out.write_message(cc(101, 127))?;
+    out.write_message(cc(100, 127))
+}
+
+/// The zone and the pitch bend range of its channels
+fn mpe_setup<P: Output>(out: &mut P) -> PmResult<()> {
+    rpn(out, MANAGER, 6, MEMBERS)?;
+    for ch in MANAGER + 1..MANAGER + 1 + MEMBERS {
+        rpn(out, ch, 0, BEND_RANGE as u8)?
+    }
+    Ok(())
+}
+
+// A lost message isn't worth stopping the music for
+impl<O: Output> Play for MusicBox<O> {
+    fn notes(&self) -> &[i32] {
+        &self.notes
+    }
+
+    fn tuning(&self) -> &Tuning {
+        &self.tuning
+    }
+
+    fn retune(&mut self, tuning: Tuning) {
+        drop(MusicBox::retune(self, tuning))
+    }
+
+    fn note_on(&mut self, note: i32, velocity: u8) {
+        drop(MusicBox::note_on(self, note, velocity))
+    }
+
+    fn note_off(&mut self, note: i32) {
+        drop(MusicBox::note_off(self, note))
+    }
+
+    fn expression(&mut self, note: i32, bend: f32, timbre: Option<f32>) {
+        drop(MusicBox::expression(self, note, bend, timbre))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keeps everything that is written to it
+    #[derive(Default)]
+    struct Capture {
+        messages: Vec<MidiMessage>,
+        sysex: Vec<Vec<u8>>,
+    }
+
+    impl Output for Capture {
+        fn write_message(&mut self, msg: MidiMessage) -> PmResult<()> {
+            self.messages.push(msg);
+            Ok(())
+        }
+
+        fn write_sysex(&mut self, msg: &[u8]) -> PmResult<()> {
+            self.sysex.push(msg.to_vec());
+            Ok(())
+        }
+    }
+
+    fn mts_box(divisions: i32) -> MusicBox<Capture> {
+        MusicBox::new(Capture::default(), Mode::Mts, Tuning::edo(divisions))
+    }
+
+    fn mpe_box() -> MusicBox<Capture> {
+        MusicBox::new(Capture::default(), Mode::Mpe, Tuning::edo(31))
+    }
+
+    fn sent(the_box: &mut MusicBox<Capture>) -> Vec<(u8, u8, u8)> {
+        the_box.output_mut().messages.drain(..).map(|m| (m.status, m.data1, m.data2)).collect()
+    }
+
+    #[test]
+    fn bulk_dump() {
+        let mut the_box = mts_box(31);
+        the_box.setup().unwrap();
+
+        let sysex = &the_box.output_mut().sysex;
+        assert_eq!(sysex.len(), 1);
+        let dump = &sysex[0];
+
+        assert_eq!(&dump[..6], &[0xf0, 0x7e, ALL_DEVICES, 0x08, 0x01, TUNING_PROGRAM]);
+        assert_eq!(&dump[6..22], TUNING_NAME);
+        assert_eq!(dump.len(), 22 + 128 * 3 + 2);
+        assert_eq!(dump[dump.len() - 1], 0xf7);
+
+        let checksum = dump[1..dump.len() - 2].iter().fold(0, |a, b| a ^ b) & 0x7f;
+        assert_eq!(dump[dump.len() - 2], checksum);
+        assert!(dump[1..dump.len() - 1].iter().all(|&b| b < 0x80));
+    }
+
+    #[test]
+    fn edo12_keys_stay() {
+        let mut the_box = mts_box(12);
+        the_box.setup().unwrap();
+
+        let dump = &the_box.output_mut().sysex[0];
+        for k in 0..128 {
+            assert_eq!(&dump[22 + 3 * k..25 + 3 * k], &[k as u8, 0, 0], "key {}", k)
+        }
+    }
+
+    #[test]
+    fn retune_in_chunks() {
+        let mut the_box = mts_box(12);
+        the_box.retune(Tuning::edo(31)).unwrap();
+
+        let sysex = &the_box.output_mut().sysex;
+        let mut keys = vec![];
+        for msg in sysex {
+            let count = msg[6] as usize;
+            assert!(count <= 127);
+            assert_eq!(&msg[..6], &[0xf0, 0x7f, ALL_DEVICES, 0x08, 0x02, TUNING_PROGRAM]);
+            assert_eq!(msg.len(), 7 + 4 * count + 1);
+            assert_eq!(msg[msg.len() - 1], 0xf7);
+
+            keys.extend(msg[7..msg.len() - 1].chunks(4).map(|entry| entry[0]))
+        }
+        assert_eq!(keys, (0..128).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn period_blocks_follow_the_tuning() {
+        let mut the_box = MusicBox::new(Capture::default(), Mode::Plain, Tuning::edo(12));
+        the_box.channels = Channels::Periods { home: 2 };
+
+        the_box.note_on(13, 100).unwrap();
+        the_box.note_off(13).unwrap();
+        the_box.retune(Tuning::edo(31)).unwrap();
+        the_box.note_on(13, 100).unwrap();
+
+        let notes: Vec<_> = the_box.output_mut().messages.iter()
+            .map(|m| (m.status, m.data1))
+            .collect();
+        assert_eq!(notes, vec![(0x93, 1), (0x83, 1), (0x92, 13)]);
+    }
+
+    #[test]
+    fn mpe_zone_setup() {
+        let mut the_box = mpe_box();
+        the_box.setup().unwrap();
+
+        let messages = sent(&mut the_box);
+        assert_eq!(messages.len(), 6 * 16);
+        // 15 member channels on the manager channel
+        assert_eq!(&messages[..6], &[
+            (0xb0, 101, 0), (0xb0, 100, 6), (0xb0, 6, 15), (0xb0, 38, 0), (0xb0, 101, 127), (0xb0, 100, 127),
+        ]);
+        // A bend range of 48 semitones on every member channel
+        for (ch, rpn) in (1..16).zip(messages[6..].chunks(6)) {
+            assert_eq!(rpn, &[
+                (0xb0 | ch, 101, 0), (0xb0 | ch, 100, 0), (0xb0 | ch, 6, 48),
+                (0xb0 | ch, 38, 0), (0xb0 | ch, 101, 127), (0xb0 | ch, 100, 127),
+            ][..]);
+        }
+    }
+
+    #[test]
+    fn mpe_bends_the_nearest_key() {
+        let mut the_box = mpe_box();
+        // 3 steps of 31-EDO are 116 cents, 16 above key 61
+        the_box.note_on(3, 100).unwrap();
+
+        let bend = pitch_bend(36.0 / 31.0 - 1.0);
+        assert_eq!(bend, 8220);
+        assert_eq!(sent(&mut the_box), vec![
+            (0xe1, (bend & 0x7f) as u8, (bend >> 7) as u8),
+            (0x91, 61, 100),
+        ]);
+    }
+
+    #[test]
+    fn mpe_steals_the_oldest_voice() {
+        let mut the_box = mpe_box();
+        for note in 0..15 {
+            the_box.note_on(note, 100).unwrap()
+        }
+        let channels: Vec<_> = sent(&mut the_box).into_iter()
+            .filter(|m| m.0 & 0xf0 == 0x90)
+            .map(|m| m.0 & 0x0f)
+            .collect();
+        assert_eq!(channels, (1..16).collect::<Vec<u8>>());
+
+        // Every channel is busy, the first note makes room
+        the_box.note_on(15, 100).unwrap();
+        let messages = sent(&mut the_box);
+        assert_eq!(messages[0], (0x81, 60, 64));
+        assert_eq!((messages[1].0, messages[2]), (0xe1, (0x91, 66, 100)));
+
+        // It's still held, but has nothing left to turn off
+        assert!(the_box.notes.contains(&0));
+        the_box.note_off(0).unwrap();
+        assert!(sent(&mut the_box).is_empty());
+    }
+}
@@ -0,0 +1,190 @@
+use std::time::{Duration, Instant};
+
+use portmidi::{MidiMessage, Result as PmResult};
+
+use midi::{MusicBox, Output};
+use smf::{self, Smf, Event, Message};
+
+/// Ticks per quarter note
+const DIVISION: u16 = 480;
+/// 120 BPM, so a tick is about a millisecond
+const TEMPO: u32 = 500_000;
+
+/// Passes everything on to the output, keeping a copy while recording
+#[derive(Debug)]
+pub struct Recorder<O> {
+    out: O,
+    take: Option<(Instant, Vec<(Duration, Message)>)>,
+    /// Note names of the layout in use, for the text events
+    pub names: Vec<String>,
+}
+
+impl<O> Recorder<O> {
+    pub fn new(out: O) -> Self {
+        Recorder { out, take: None, names: vec![] }
+    }
+
+    pub fn recording(&self) -> bool {
+        self.take.is_some()
+    }
+
+    pub fn start(&mut self) {
+        self.take = Some((Instant::now(), vec![]))
+    }
+
+    /// Put the messages into the take without sending them, like the setup the synth already has
+    pub fn add(&mut self, messages: Vec<Message>) {
+        for message in messages {
+            self.keep(message)
+        }
+    }
+
+    /// Stop recording, the take is a Type 1 MIDI file with the tempo on the first track
+    pub fn stop(&mut self) -> Option<Smf> {
+        let (_, events) = self.take.take()?;
+
+        let ticks_per_second = DIVISION as f64 * 1e6 / TEMPO as f64;
+        let tick = |d: Duration| {
+            let seconds = d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9;
+            (seconds * ticks_per_second).round() as u64
+        };
+
+        let tempo = vec![
+            Event { tick: 0, message: Message::Meta { kind: smf::TRACK_NAME, data: b"31key".to_vec() } },
+            Event { tick: 0, message: Message::Meta {
+                kind: smf::TEMPO,
+                data: vec![(TEMPO >> 16) as u8, (TEMPO >> 8) as u8, TEMPO as u8],
+            } },
+        ];
+        let performance = events.into_iter()
+            .map(|(at, message)| Event { tick: tick(at), message })
+            .collect();
+
+        Some(Smf {
+            format: 1,
+            division: DIVISION,
+            tracks: vec![tempo, performance],
+        })
+    }
+
+    fn keep(&mut self, message: Message) {
+        if let Some((start, ref mut events)) = self.take {
+            events.push((start.elapsed(), message))
+        }
+    }
+}
+
+/// Stop recording the box, ending the notes it still holds in the take only so that
+/// they don't hang when the file is played
+pub fn stop<O: Output>(the_box: &mut MusicBox<Recorder<O>>) -> Option<Smf> {
+    let mut notes_off = vec![];
+    drop(the_box.write_notes_off(&mut notes_off));
+    the_box.output_mut().add(notes_off);
+    the_box.output_mut().stop()
+}
+
+/// Keeps the messages, sending them nowhere
+impl Output for Vec<Message> {
+    fn write_message(&mut self, msg: MidiMessage) -> PmResult<()> {
+        self.push(Message::Midi { status: msg.status, data1: msg.data1, data2: msg.data2 });
+        Ok(())
+    }
+
+    fn write_sysex(&mut self, msg: &[u8]) -> PmResult<()> {
+        self.push(Message::SysEx(msg.to_vec()));
+        Ok(())
+    }
+}
+
+impl<O: Output> Output for Recorder<O> {
+    fn write_message(&mut self, msg: MidiMessage) -> PmResult<()> {
+        self.keep(Message::Midi { status: msg.status, data1: msg.data1, data2: msg.data2 });
+        self.out.write_message(msg)
+    }
+
+    fn write_sysex(&mut self, msg: &[u8]) -> PmResult<()> {
+        self.keep(Message::SysEx(msg.to_vec()));
+        self.out.write_sysex(msg)
+    }
+
+    fn note_step(&mut self, step: i32) {
+        let n = self.names.len() as i32;
+        let name = if n > 0 { Some(self.names[((step % n + n) % n) as usize].clone()) } else { None };
+        let text = smf::step_text(step, name.as_ref().map(|n| n.as_str()));
+        self.keep(Message::Meta { kind: smf::TEXT, data: text });
+        self.out.note_step(step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use layout::Tuning;
+    use midi::Mode;
+
+    fn recording_box(mode: Mode) -> MusicBox<Recorder<Vec<Message>>> {
+        let mut the_box = MusicBox::new(Recorder::new(vec![]), mode, Tuning::edo(31));
+        the_box.output_mut().names = Tuning::edo(31).note_names();
+        the_box.output_mut().start();
+        let mut setup = vec![];
+        the_box.write_setup(&mut setup).unwrap();
+        the_box.output_mut().add(setup);
+        the_box
+    }
+
+    fn take(mode: Mode) -> (Vec<Message>, Vec<Message>) {
+        let mut the_box = recording_box(mode);
+        the_box.note_on(3, 100).unwrap();
+
+        let smf = the_box.output_mut().stop().unwrap();
+        let sent = the_box.output_mut().out.clone();
+        let recorded = smf.tracks[1].iter().map(|e| e.message.clone()).collect();
+        (sent, recorded)
+    }
+
+    fn is_sysex(message: &Message) -> bool {
+        match *message {
+            Message::SysEx(_) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn plain_takes_have_the_tuning() {
+        let (sent, recorded) = take(Mode::Plain);
+
+        assert_eq!(sent, vec![Message::Midi { status: 0x90, data1: 63, data2: 100 }]);
+        assert!(is_sysex(&recorded[0]));
+        assert_eq!(&recorded[1..], &[
+            Message::Meta { kind: smf::TEXT, data: smf::step_text(3, Some("Db")) },
+            Message::Midi { status: 0x90, data1: 63, data2: 100 },
+        ]);
+    }
+
+    #[test]
+    fn setup_is_not_sent_again() {
+        let (sent, recorded) = take(Mode::Mpe);
+
+        // The bend and the note on
+        assert_eq!(sent.len(), 2);
+        assert_eq!(recorded.len(), 6 * 16 + 1 + 2);
+    }
+
+    #[test]
+    fn held_notes_end_with_the_take() {
+        let mut the_box = recording_box(Mode::Plain);
+        the_box.note_on(3, 100).unwrap();
+        the_box.note_on(5, 100).unwrap();
+        the_box.note_off(3).unwrap();
+
+        let smf = stop(&mut the_box).unwrap();
+        let recorded: Vec<_> = smf.tracks[1].iter().map(|e| e.message.clone()).collect();
+
+        // Only the one that was still held, and the synth doesn't get it
+        let off = Message::Midi { status: 0x80, data1: 65, data2: 64 };
+        assert_eq!(recorded.last(), Some(&off));
+        assert_eq!(recorded.iter().filter(|&m| *m == off).count(), 1);
+        assert_eq!(the_box.output_mut().out.len(), 3);
+        assert!(!the_box.output_mut().recording());
+    }
+}
@@ -5,6 +5,7 @@ use gfx::{Encoder, PipelineState};
 use gfx_device_gl as gl;
 
 use super::{ColorFormat, DepthFormat};
+use super::font;
 
 const BLACK: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
 
@@ -23,6 +24,45 @@ gfx_defines! {
 pub trait Render {
     fn render_fan<V>(&mut self, iter: V)
     where V: ::std::iter::IntoIterator<Item=Vertex>;
+
+    /// Draw a line of text centered at `center`, `height` is the one of a character and
+    /// `width` is the one of the window relative to its height
+    fn render_text(&mut self, text: &str, center: [f32; 2], height: f32, width: f32, color: [f32; 4]) {
+        let pixel = height / font::HEIGHT as f32;
+        let advance = (font::WIDTH + 1) as f32 * pixel;
+        let len = text.chars().count() as f32;
+        let x0 = center[0] - (len * advance - pixel) / 2.0;
+        let y0 = center[1] + height / 2.0;
+
+        for (i, c) in text.chars().enumerate() {
+            let left = x0 + i as f32 * advance;
+
+            for (row, bits) in font::glyph(c).iter().enumerate() {
+                let lit = |col: usize| bits >> (font::WIDTH - 1 - col) & 1 == 1;
+                let top = y0 - row as f32 * pixel;
+
+                // Every run of lit pixels in a row is one quad
+                let mut col = 0;
+                while col < font::WIDTH {
+                    if !lit(col) {
+                        col += 1;
+                        continue
+                    }
+                    let start = col;
+                    while col < font::WIDTH && lit(col) {
+                        col += 1
+                    }
+
+                    let (x1, x2) = (left + start as f32 * pixel, left + col as f32 * pixel);
+                    let corners = [(x1, top), (x2, top), (x2, top - pixel), (x1, top - pixel)];
+                    self.render_fan(corners.iter().map(|&(x, y)| Vertex {
+                        pos: [x / width, y],
+                        color,
+                    }))
+                }
+            }
+        }
+    }
 }
 
 pub struct Renderer {
@@ -31,7 +71,7 @@ pub struct Renderer {
     out_color: RenderTargetView<gl::Resources, ColorFormat>,
     pso: PipelineState<gl::Resources, pipe::Meta>,
     vertices: Vec<Vertex>,
-    indices: Vec<u16>,
+    indices: Vec<u32>,
 }
 
 impl Renderer {
@@ -90,12 +130,12 @@ impl Renderer {
 impl Render for Renderer {
     fn render_fan<V>(&mut self, iter: V)
     where V: ::std::iter::IntoIterator<Item=Vertex> {
-        let i0 = self.vertices.len() as u16;
+        let i0 = self.vertices.len() as u32;
         let mut vs = iter.into_iter();
         self.vertices.push(vs.next().unwrap());
         self.vertices.push(vs.next().unwrap());
         for (i, v) in vs.enumerate() {
-            let i = i as u16 + 1;
+            let i = i as u32 + 1;
             self.vertices.push(v);
             self.indices.extend(&[i0, i0+i, i0+i+1]);
         }
@@ -0,0 +1,455 @@
+use std::fmt;
+use std::io::{self, Write};
+
+/// Standard MIDI File
+#[derive(Debug, Clone, PartialEq)]
+pub struct Smf {
+    pub format: u16,
+    /// Ticks per quarter note, or SMPTE frames and ticks per frame if the top bit is set
+    pub division: u16,
+    pub tracks: Vec<Vec<Event>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// In ticks from the start of the track
+    pub tick: u64,
+    pub message: Message,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Midi { status: u8, data1: u8, data2: u8 },
+    /// Including the leading 0xF0
+    SysEx(Vec<u8>),
+    Meta { kind: u8, data: Vec<u8> },
+}
+
+/// A note on, or a note off if the velocity is 0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoteEvent {
+    /// In seconds from the start
+    pub time: f64,
+    pub step: i32,
+    pub velocity: u8,
+}
+
+pub const TEXT: u8 = 0x01;
+pub const TRACK_NAME: u8 = 0x03;
+const END_OF_TRACK: u8 = 0x2f;
+pub const TEMPO: u8 = 0x51;
+/// Microseconds per quarter note when the file doesn't say
+const DEFAULT_TEMPO: u32 = 500_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl Error {
+    fn new<S: Into<String>>(offset: usize, message: S) -> Self {
+        Error { offset, message: message.into() }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "byte {}: {}", self.offset, self.message)
+    }
+}
+
+struct Reader<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn eof(&self) -> bool {
+        self.pos >= self.src.len()
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.src.len() - self.pos < n {
+            return Err(Error::new(self.src.len(), "unexpected end of file"))
+        }
+
+        let bytes = &self.src[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        self.bytes(2).map(|b| (b[0] as u16) << 8 | b[1] as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        self.bytes(4).map(|b| b.iter().fold(0, |n, &b| n << 8 | b as u32))
+    }
+
+    /// Variable-length quantity, 7 bits a byte, the top bit set on all but the last one
+    fn vlq(&mut self) -> Result<u32, Error> {
+        let start = self.pos;
+        let mut n = 0;
+        for _ in 0..4 {
+            let b = self.byte()?;
+            n = n << 7 | (b & 0x7f) as u32;
+            if b & 0x80 == 0 {
+                return Ok(n)
+            }
+        }
+
+        Err(Error::new(start, "variable-length number is longer than 4 bytes"))
+    }
+}
+
+pub fn parse(src: &[u8]) -> Result<Smf, Error> {
+    let mut r = Reader { src, pos: 0 };
+
+    if r.bytes(4)? != b"MThd" {
+        return Err(Error::new(0, "not a MIDI file"))
+    }
+    let len = r.u32()? as usize;
+    let format = r.u16()?;
+    let ntracks = r.u16()?;
+    let start = r.pos;
+    let division = r.u16()?;
+    if division & 0x8000 != 0 {
+        match (division >> 8) as u8 as i8 {
+            -24 | -25 | -29 | -30 => (),
+            fps => return Err(Error::new(start, format!(
+                "{} frames per second isn't an SMPTE rate", -(fps as i32)
+            ))),
+        }
+    }
+    if division & 0x7fff == 0 || division & 0x80ff == 0x8000 {
+        return Err(Error::new(start, "no ticks in the division"))
+    }
+    r.bytes(len.saturating_sub(6))?;
+
+    let mut tracks = vec![];
+    while !r.eof() && tracks.len() < ntracks as usize {
+        let kind = r.bytes(4)?;
+        let len = r.u32()? as usize;
+        let start = r.pos;
+        let chunk = r.bytes(len)?;
+
+        // Chunks of other kinds are to be skipped
+        if kind == b"MTrk" {
+            tracks.push(parse_track(chunk).map_err(|e| Error::new(start + e.offset, e.message))?)
+        }
+    }
+
+    Ok(Smf { format, division, tracks })
+}
+
+fn parse_track(src: &[u8]) -> Result<Vec<Event>, Error> {
+    let mut r = Reader { src, pos: 0 };
+    let mut events = vec![];
+    let mut tick = 0;
+    let mut running = None;
+
+    while !r.eof() {
+        tick += r.vlq()? as u64;
+
+        let start = r.pos;
+        let message = match r.byte()? {
+            0xff => {
+                let kind = r.byte()?;
+                let len = r.vlq()? as usize;
+                Message::Meta { kind, data: r.bytes(len)?.to_vec() }
+            },
+            b @ 0xf0 | b @ 0xf7 => {
+                let len = r.vlq()? as usize;
+                let mut data = if b == 0xf0 { vec![0xf0] } else { vec![] };
+                data.extend(r.bytes(len)?);
+                Message::SysEx(data)
+            },
+            b => {
+                // Without a status byte the last one goes on
+                let (status, data1) = match (b, running) {
+                    (b, _) if b >= 0x80 => (b, r.byte()?),
+                    (b, Some(status)) => (status, b),
+                    _ => return Err(Error::new(start, "data byte without a status")),
+                };
+                running = Some(status);
+
+                let data2 = match status & 0xf0 {
+                    0xc0 | 0xd0 => 0,
+                    _ => r.byte()?,
+                };
+                Message::Midi { status, data1, data2 }
+            },
+        };
+
+        events.push(Event { tick, message });
+    }
+
+    Ok(events)
+}
+
+impl Smf {
+    /// Events of all the tracks in order, with their times in seconds
+    pub fn timed_events(&self) -> Vec<(f64, &Message)> {
+        let mut events: Vec<&Event> = self.tracks.iter().flat_map(|t| t.iter()).collect();
+        events.sort_by_key(|e| e.tick);
+
+        if self.division & 0x8000 != 0 {
+            let fps = match (self.division >> 8) as u8 as i8 {
+                -29 => 29.97,
+                fps => -fps as f64,
+            };
+            let per_frame = (self.division & 0xff) as f64;
+            return events.into_iter()
+                .map(|e| (e.tick as f64 / fps / per_frame, &e.message))
+                .collect()
+        }
+
+        // Tempo changes in any track apply to every one
+        let per_quarter = self.division as f64;
+        let (mut tempo, mut last_tick, mut time) = (DEFAULT_TEMPO, 0, 0.0);
+        events.into_iter().map(|e| {
+            time += (e.tick - last_tick) as f64 / per_quarter * tempo as f64 / 1e6;
+            last_tick = e.tick;
+            if let Message::Meta { kind: TEMPO, ref data } = e.message {
+                if data.len() == 3 {
+                    tempo = data.iter().fold(0, |n, &b| n << 8 | b as u32)
+                }
+            }
+            (time, &e.message)
+        }).collect()
+    }
+
+    /// Notes as steps. A `step` text event right before a note on gives its step,
+    /// otherwise key 60 is step 0 like in the plain output
+    pub fn note_events(&self) -> Vec<NoteEvent> {
+        let mut events = vec![];
+        let mut next_step = None;
+        // Steps of the keys that are down, by channel and key
+        let mut held: Vec<((u8, u8), i32)> = vec![];
+
+        for (time, message) in self.timed_events() {
+            match *message {
+                Message::Meta { kind: TEXT, ref data } =>
+                    next_step = parse_step(data),
+                Message::Midi { status, data1, data2 } if status & 0xf0 == 0x90 && data2 > 0 => {
+                    let step = next_step.take().unwrap_or(data1 as i32 - 60);
+                    held.push(((status & 0x0f, data1), step));
+                    events.push(NoteEvent { time, step, velocity: data2 })
+                },
+                Message::Midi { status, data1, .. } if status & 0xf0 == 0x80 || status & 0xf0 == 0x90 => {
+                    let key = (status & 0x0f, data1);
+                    let step = match held.iter().position(|h| h.0 == key) {
+                        Some(i) => held.remove(i).1,
+                        None => data1 as i32 - 60,
+                    };
+                    events.push(NoteEvent { time, step, velocity: 0 })
+                },
+                _ => (),
+            }
+        }
+
+        events
+    }
+}
+
+/// The text event that goes before a note on, like `step 5 ^D`
+pub fn step_text(step: i32, name: Option<&str>) -> Vec<u8> {
+    match name {
+        Some(name) => format!("step {} {}", step, name).into_bytes(),
+        None => format!("step {}", step).into_bytes(),
+    }
+}
+
+/// The step of a text event, the name after it is only for people to read
+fn parse_step(text: &[u8]) -> Option<i32> {
+    let text = ::std::str::from_utf8(text).ok()?;
+    if text.starts_with("step ") { text[5..].split_whitespace().next()?.parse().ok() } else { None }
+}
+
+fn write_vlq<W: Write>(out: &mut W, n: u32) -> io::Result<()> {
+    let mut bytes = vec![(n & 0x7f) as u8];
+    let mut n = n >> 7;
+    while n > 0 {
+        bytes.push((n & 0x7f) as u8 | 0x80);
+        n >>= 7
+    }
+    bytes.reverse();
+
+    out.write_all(&bytes)
+}
+
+fn track_bytes(events: &[Event]) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    let mut tick = 0;
+
+    for event in events {
+        write_vlq(&mut out, (event.tick.saturating_sub(tick)) as u32)?;
+        tick = event.tick.max(tick);
+
+        match event.message {
+            Message::Midi { status, data1, data2 } => {
+                out.extend(&[status, data1]);
+                if status & 0xf0 != 0xc0 && status & 0xf0 != 0xd0 {
+                    out.push(data2)
+                }
+            },
+            Message::SysEx(ref data) => match data.split_first() {
+                // The leading 0xF0 isn't counted in the length
+                Some((&0xf0, rest)) => {
+                    out.push(0xf0);
+                    write_vlq(&mut out, rest.len() as u32)?;
+                    out.extend(rest)
+                },
+                // An escape, the bytes go as they are
+                _ => {
+                    out.push(0xf7);
+                    write_vlq(&mut out, data.len() as u32)?;
+                    out.extend(data)
+                },
+            },
+            Message::Meta { kind, ref data } => {
+                out.extend(&[0xff, kind]);
+                write_vlq(&mut out, data.len() as u32)?;
+                out.extend(data)
+            },
+        }
+    }
+
+    write_vlq(&mut out, 0)?;
+    out.extend(&[0xff, END_OF_TRACK, 0]);
+
+    Ok(out)
+}
+
+pub fn write<W: Write>(mut out: W, smf: &Smf) -> io::Result<()> {
+    fn u16_be(x: u16) -> [u8; 2] {
+        [(x >> 8) as u8, x as u8]
+    }
+    fn u32_be(x: u32) -> [u8; 4] {
+        [(x >> 24) as u8, (x >> 16) as u8, (x >> 8) as u8, x as u8]
+    }
+
+    out.write_all(b"MThd")?;
+    out.write_all(&u32_be(6))?;
+    out.write_all(&u16_be(smf.format))?;
+    out.write_all(&u16_be(smf.tracks.len() as u16))?;
+    out.write_all(&u16_be(smf.division))?;
+
+    for track in &smf.tracks {
+        let bytes = track_bytes(track)?;
+        out.write_all(b"MTrk")?;
+        out.write_all(&u32_be(bytes.len() as u32))?;
+        out.write_all(&bytes)?
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(division: [u8; 2], track: &[u8]) -> Vec<u8> {
+        let mut src = b"MThd\0\0\0\x06\0\0\0\x01".to_vec();
+        src.extend(&division);
+        src.extend(b"MTrk");
+        src.extend(&[0, 0, 0, track.len() as u8]);
+        src.extend(track);
+        src
+    }
+
+    fn note(time: f64, step: i32, velocity: u8) -> NoteEvent {
+        NoteEvent { time, step, velocity }
+    }
+
+    #[test]
+    fn running_status_and_tempo() {
+        let smf = parse(&file([0, 96], &[
+            0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, // 120 BPM
+            0x00, 0x90, 60, 64,
+            0x60, 60, 0, // Running status, a note on with velocity 0
+            0x00, 62, 64,
+            0x00, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40, // 60 BPM
+            0x60, 0x80, 62, 64,
+            0x00, 0xff, 0x2f, 0x00,
+        ])).unwrap();
+
+        assert_eq!(smf.tracks.len(), 1);
+        assert_eq!(smf.tracks[0][2], Event { tick: 96, message: Message::Midi { status: 0x90, data1: 60, data2: 0 } });
+        assert_eq!(smf.tracks[0][3], Event { tick: 96, message: Message::Midi { status: 0x90, data1: 62, data2: 64 } });
+        assert_eq!(smf.note_events(), vec![note(0.0, 0, 64), note(0.5, 0, 0), note(0.5, 2, 64), note(1.5, 2, 0)]);
+    }
+
+    #[test]
+    fn smpte_division() {
+        // 25 frames a second, 40 ticks a frame
+        let smf = parse(&file([0xe7, 40], &[
+            0x00, 0x90, 60, 64,
+            0x83, 0x74, 0x80, 60, 64, // 500 ticks
+            0x00, 0xff, 0x2f, 0x00,
+        ])).unwrap();
+
+        assert_eq!(smf.note_events(), vec![note(0.0, 0, 64), note(0.5, 0, 0)]);
+    }
+
+    #[test]
+    fn steps_of_recordings() {
+        let text = step_text(-40, Some("vEb"));
+        let mut track = vec![0x00, 0xff, TEXT, text.len() as u8];
+        track.extend(text);
+        track.extend(&[0x00, 0x90, 60, 64, 0x60, 0x80, 60, 64]);
+
+        let smf = parse(&file([0, 96], &track)).unwrap();
+        assert_eq!(smf.note_events(), vec![note(0.0, -40, 64), note(0.5, -40, 0)]);
+    }
+
+    #[test]
+    fn sysex_round_trip() {
+        let smf = Smf {
+            format: 0,
+            division: 96,
+            tracks: vec![vec![
+                Event { tick: 0, message: Message::SysEx(vec![0xf0, 0x7e, 0x7f, 0x09, 0x01, 0xf7]) },
+                Event { tick: 10, message: Message::SysEx(vec![0xf3, 0x01]) },
+                Event { tick: 20, message: Message::SysEx(vec![]) },
+                Event { tick: 30, message: Message::Meta { kind: END_OF_TRACK, data: vec![] } },
+            ]],
+        };
+
+        let mut bytes = vec![];
+        write(&mut bytes, &smf).unwrap();
+        let mut parsed = parse(&bytes).unwrap();
+        // The writer ends every track by itself
+        parsed.tracks[0].pop();
+
+        assert_eq!(parsed, smf);
+    }
+
+    #[test]
+    fn truncated() {
+        let src = file([0, 96], &[0x00, 0x90, 60]);
+
+        assert_eq!(parse(&src).unwrap_err(), Error::new(src.len(), "unexpected end of file"));
+    }
+
+    #[test]
+    fn bad_division() {
+        let track = [0x00, 0x90, 60, 64];
+
+        assert_eq!(parse(&file([0, 0], &track)).unwrap_err(), Error::new(12, "no ticks in the division"));
+        assert_eq!(parse(&file([0xe7, 0], &track)).unwrap_err(), Error::new(12, "no ticks in the division"));
+        assert_eq!(
+            parse(&file([0x80, 40], &track)).unwrap_err(),
+            Error::new(12, "128 frames per second isn't an SMPTE rate")
+        );
+        assert_eq!(
+            parse(&file([0xe6, 40], &track)).unwrap_err(),
+            Error::new(12, "26 frames per second isn't an SMPTE rate")
+        );
+    }
+}
@@ -0,0 +1,424 @@
+use std::f32::consts::PI;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use cpal;
+
+use layout::Tuning;
+use midi::Play;
+use smf::NoteEvent;
+
+/// Voices playing at once, the oldest one is cut off after that
+const MAX_VOICES: usize = 32;
+const PARTIALS: usize = 8;
+/// Inharmonicity of the piano strings
+const STIFFNESS: f32 = 0.0004;
+
+/// Sounds of the synth
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Preset {
+    /// A few sine partials, timbre makes it brighter
+    Sine,
+    /// A sawtooth through a lowpass filter, timbre opens the filter
+    Saw,
+    /// Slightly stretched partials that die away, the higher the sooner
+    Piano,
+}
+
+impl Preset {
+    pub fn all() -> &'static [Preset] {
+        use self::Preset::*;
+        &[Sine, Saw, Piano]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::Sine => "sine",
+            Preset::Saw => "saw",
+            Preset::Piano => "piano",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Preset::all().iter().cloned().find(|p| p.name() == name)
+    }
+
+    fn envelope(self) -> Envelope {
+        match self {
+            Preset::Sine => Envelope { attack: 0.01, decay: 0.3, sustain: 0.8, release: 0.15 },
+            Preset::Saw => Envelope { attack: 0.005, decay: 0.4, sustain: 0.6, release: 0.1 },
+            // The partials decay by themselves
+            Preset::Piano => Envelope { attack: 0.002, decay: 1.0, sustain: 1.0, release: 0.25 },
+        }
+    }
+}
+
+/// Times are in seconds
+#[derive(Debug, Clone, Copy)]
+struct Envelope {
+    attack: f32,
+    decay: f32,
+    sustain: f32,
+    release: f32,
+}
+
+impl Envelope {
+    /// Level at `t` seconds after the note on, the note off was at `released`
+    fn level(&self, t: f32, released: Option<f32>) -> f32 {
+        let held = |t: f32|
+            if t < self.attack { t / self.attack }
+            else { self.sustain + (1.0 - self.sustain) * (-(t - self.attack) / self.decay).exp() };
+
+        match released {
+            Some(r) if t >= r => held(r) * (-(t - r) / self.release).exp(),
+            _ => held(t),
+        }
+    }
+
+    /// Whether a note released at `released` is silent at `t`
+    fn done(&self, t: f32, released: Option<f32>) -> bool {
+        released.map_or(false, |r| t - r > 8.0 * self.release)
+    }
+}
+
+#[derive(Debug)]
+struct Voice {
+    note: i32,
+    freq: f32,
+    gain: f32,
+    /// In cents
+    bend: f32,
+    /// From 0 to 1
+    timbre: f32,
+    /// Phases of the partials in periods
+    phases: [f32; PARTIALS],
+    /// State of the filter
+    low: f32,
+    band: f32,
+    /// In samples since the note on
+    age: u32,
+    released: Option<u32>,
+}
+
+impl Voice {
+    fn new(note: i32, freq: f32, velocity: u8) -> Self {
+        Voice {
+            note, freq,
+            gain: (velocity as f32 / 127.0).powf(1.5),
+            bend: 0.0,
+            timbre: 0.5,
+            phases: [0.0; PARTIALS],
+            low: 0.0,
+            band: 0.0,
+            age: 0,
+            released: None,
+        }
+    }
+
+    fn sample(&mut self, preset: Preset, rate: f32) -> f32 {
+        let t = self.age as f32 / rate;
+        let freq = self.freq * (self.bend / 1200.0).exp2();
+        let nyquist = rate / 2.0;
+
+        let out = match preset {
+            Preset::Sine => {
+                let (mut out, mut total) = (0.0, 0.0);
+                for k in 0..PARTIALS {
+                    let n = (k + 1) as f32;
+                    let amp = if n * freq < nyquist { n.powf(2.0 * self.timbre - 3.0) } else { 0.0 };
+                    out += amp * (2.0 * PI * self.phases[k]).sin();
+                    total += amp;
+                    self.phases[k] = (self.phases[k] + n * freq / rate).fract()
+                }
+                if total > 0.0 { out / total } else { 0.0 }
+            },
+            Preset::Saw => {
+                let dt = freq / rate;
+                let saw = 2.0 * self.phases[0] - 1.0 - poly_blep(self.phases[0], dt);
+                self.phases[0] = (self.phases[0] + dt).fract();
+
+                // Chamberlin state variable filter, stable below a sixth of the rate
+                let cutoff = (freq * (1.0 + 15.0 * self.timbre)).min(rate / 6.0);
+                let f = 2.0 * (PI * cutoff / rate).sin();
+                self.low += f * self.band;
+                let high = saw - self.low - 0.7 * self.band;
+                self.band += f * high;
+                self.low
+            },
+            Preset::Piano => {
+                let bright = 0.3 + 0.7 * self.gain;
+                let mut out = 0.0;
+                for k in 0..PARTIALS {
+                    let n = (k + 1) as f32;
+                    let ratio = n * (1.0 + STIFFNESS * n * n).sqrt();
+                    if ratio * freq < nyquist {
+                        let amp = bright.powi(k as i32) / n * (-t * (0.4 + 0.6 * n)).exp();
+                        out += amp * (2.0 * PI * self.phases[k]).sin();
+                    }
+                    self.phases[k] = (self.phases[k] + ratio * freq / rate).fract()
+                }
+                out
+            },
+        };
+
+        let released = self.released.map(|r| r as f32 / rate);
+        self.age += 1;
+
+        out * self.gain * preset.envelope().level(t, released)
+    }
+
+    fn done(&self, preset: Preset, rate: f32) -> bool {
+        let released = self.released.map(|r| r as f32 / rate);
+        preset.envelope().done(self.age as f32 / rate, released)
+    }
+}
+
+/// Smooths the jump of a sawtooth, so it doesn't alias as much
+fn poly_blep(phase: f32, dt: f32) -> f32 {
+    if phase < dt {
+        let t = phase / dt;
+        2.0 * t - t * t - 1.0
+    } else if phase > 1.0 - dt {
+        let t = (phase - 1.0) / dt;
+        t * t + 2.0 * t + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// Plays the exact frequencies of a tuning
+#[derive(Debug)]
+pub struct Synth {
+    rate: f32,
+    preset: Preset,
+    tuning: Tuning,
+    pub notes: Vec<i32>,
+    voices: Vec<Voice>,
+}
+
+impl Synth {
+    pub fn new(rate: u32, preset: Preset, tuning: Tuning) -> Self {
+        Synth {
+            rate: rate as f32,
+            preset,
+            tuning,
+            notes: vec![],
+            voices: vec![],
+        }
+    }
+
+    /// Fill the buffer with mono samples
+    pub fn render(&mut self, out: &mut [f32]) {
+        let (preset, rate) = (self.preset, self.rate);
+
+        for sample in out.iter_mut() {
+            let mix: f32 = self.voices.iter_mut().map(|v| v.sample(preset, rate)).sum();
+            // Soft clipping when a lot of voices sound at once
+            *sample = (0.3 * mix).tanh()
+        }
+
+        self.voices.retain(|v| !v.done(preset, rate))
+    }
+}
+
+impl Play for Synth {
+    fn notes(&self) -> &[i32] {
+        &self.notes
+    }
+
+    fn tuning(&self) -> &Tuning {
+        &self.tuning
+    }
+
+    fn retune(&mut self, tuning: Tuning) {
+        self.tuning = tuning
+    }
+
+    fn note_on(&mut self, note: i32, velocity: u8) {
+        if self.notes.contains(&note) {
+            return
+        }
+        self.notes.push(note);
+
+        if self.voices.len() >= MAX_VOICES {
+            self.voices.remove(0);
+        }
+        self.voices.push(Voice::new(note, self.tuning.freq(note), velocity))
+    }
+
+    fn note_off(&mut self, note: i32) {
+        self.notes.retain(|&n| n != note);
+
+        for voice in self.voices.iter_mut().filter(|v| v.note == note) {
+            if voice.released.is_none() {
+                voice.released = Some(voice.age)
+            }
+        }
+    }
+
+    fn expression(&mut self, note: i32, bend: f32, timbre: Option<f32>) {
+        for voice in self.voices.iter_mut().filter(|v| v.note == note && v.released.is_none()) {
+            voice.bend = bend;
+            voice.timbre = timbre.unwrap_or(0.5)
+        }
+    }
+}
+
+/// Render the notes in order, with time for the last ones to die away
+pub fn render_notes(notes: &[NoteEvent], preset: Preset, tuning: Tuning, rate: u32) -> Vec<f32> {
+    let mut synth = Synth::new(rate, preset, tuning);
+    let mut samples = vec![];
+
+    for note in notes {
+        let at = (note.time * rate as f64).round() as usize;
+        if at > samples.len() {
+            let start = samples.len();
+            samples.resize(at, 0.0);
+            synth.render(&mut samples[start..])
+        }
+
+        match note.velocity {
+            0 => synth.note_off(note.step),
+            v => synth.note_on(note.step, v),
+        }
+    }
+
+    let start = samples.len();
+    samples.resize(start + 2 * rate as usize, 0.0);
+    synth.render(&mut samples[start..]);
+
+    samples
+}
+
+/// Start the synth on the default sound card, it runs on a thread of its own
+pub fn play(preset: Preset, tuning: Tuning) -> Result<Arc<Mutex<Synth>>, String> {
+    let device = cpal::default_output_device().ok_or("there's no sound card")?;
+    let format = device.default_output_format().map_err(|e| e.to_string())?;
+    let event_loop = cpal::EventLoop::new();
+    let stream = event_loop.build_output_stream(&device, &format).map_err(|e| e.to_string())?;
+    event_loop.play_stream(stream);
+
+    let synth = Arc::new(Mutex::new(Synth::new(format.sample_rate.0, preset, tuning)));
+    let shared = synth.clone();
+    let channels = format.channels as usize;
+
+    ::std::thread::spawn(move || {
+        let mut mono = vec![];
+
+        event_loop.run(move |_, data| {
+            use cpal::UnknownTypeOutputBuffer::*;
+
+            let buffer = match data {
+                cpal::StreamData::Output { buffer } => buffer,
+                _ => return,
+            };
+            let len = match buffer {
+                U16(ref b) => b.len(),
+                I16(ref b) => b.len(),
+                F32(ref b) => b.len(),
+            };
+            mono.resize(len / channels, 0.0);
+            shared.lock().unwrap().render(&mut mono);
+
+            // The same sample on every channel
+            match buffer {
+                U16(mut b) => for (frame, &x) in b.chunks_mut(channels).zip(&mono) {
+                    for out in frame {
+                        *out = ((x * 0.5 + 0.5) * ::std::u16::MAX as f32) as u16
+                    }
+                },
+                I16(mut b) => for (frame, &x) in b.chunks_mut(channels).zip(&mono) {
+                    for out in frame {
+                        *out = (x * ::std::i16::MAX as f32) as i16
+                    }
+                },
+                F32(mut b) => for (frame, &x) in b.chunks_mut(channels).zip(&mono) {
+                    for out in frame {
+                        *out = x
+                    }
+                },
+            }
+        })
+    });
+
+    Ok(synth)
+}
+
+/// Write mono samples as a 16-bit PCM WAV file
+pub fn write_wav<W: Write>(mut out: W, rate: u32, samples: &[f32]) -> io::Result<()> {
+    fn u16_le(x: u16) -> [u8; 2] {
+        [x as u8, (x >> 8) as u8]
+    }
+    fn u32_le(x: u32) -> [u8; 4] {
+        [x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8]
+    }
+
+    let data_len = 2 * samples.len() as u32;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&u32_le(36 + data_len))?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&u32_le(16))?;
+    out.write_all(&u16_le(1))?; // PCM
+    out.write_all(&u16_le(1))?; // Mono
+    out.write_all(&u32_le(rate))?;
+    out.write_all(&u32_le(2 * rate))?; // Bytes per second
+    out.write_all(&u16_le(2))?; // Bytes per frame
+    out.write_all(&u16_le(16))?; // Bits per sample
+
+    out.write_all(b"data")?;
+    out.write_all(&u32_le(data_len))?;
+    for &x in samples {
+        let x = (x.max(-1.0).min(1.0) * ::std::i16::MAX as f32) as i16;
+        out.write_all(&u16_le(x as u16))?
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 44100;
+
+    /// Frequency from the rising zero crossings between `from` and `to` seconds
+    fn frequency(samples: &[f32], from: f32, to: f32) -> f32 {
+        let samples = &samples[(from * RATE as f32) as usize..(to * RATE as f32) as usize];
+        let crossings = samples.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+
+        crossings as f32 / (to - from)
+    }
+
+    #[test]
+    fn plays_in_tune() {
+        let tuning = Tuning::edo(31);
+
+        for &step in &[0, 18, -13, 31] {
+            let notes = [
+                NoteEvent { time: 0.0, step, velocity: 100 },
+                NoteEvent { time: 1.0, step, velocity: 0 },
+            ];
+            let samples = render_notes(&notes, Preset::Sine, tuning.clone(), RATE);
+
+            let expected = tuning.freq(step);
+            let found = frequency(&samples, 0.2, 0.9);
+            assert!((found - expected).abs() < 0.005 * expected, "step {}: {} Hz, not {} Hz", step, found, expected)
+        }
+    }
+
+    #[test]
+    fn renders_a_tail() {
+        let notes = [
+            NoteEvent { time: 0.0, step: 0, velocity: 100 },
+            NoteEvent { time: 0.5, step: 0, velocity: 0 },
+        ];
+        let samples = render_notes(&notes, Preset::Piano, Tuning::edo(31), RATE);
+
+        assert_eq!(samples.len(), (2.5 * RATE as f32) as usize);
+        assert!(samples.iter().all(|x| x.abs() <= 1.0));
+        assert!(samples[samples.len() - 100..].iter().all(|x| x.abs() < 1e-3));
+    }
+}
@@ -1,12 +1,19 @@
 use cgmath::prelude::*;
 use cgmath::{Vector2, Vector3, Matrix2, Rad, Rotation2, Basis2};
 
+use glutin::VirtualKeyCode;
+
 use super::layout::Layout;
 
 type Xy = Vector2<f32>;
 type Qr<T> = Vector2<T>;
 type Color = Vector3<f32>;
 
+const HEX_SIZE: f32 = 80.0;
+const MARK_COLOR: Color = Vector3 { x: 0.25, y: 0.5, z: 1.0 };
+/// Hexes with a radius smaller than that in pixels are left without labels
+const MIN_LABEL_SIZE: f32 = 20.0;
+
 fn into_lrgb(rgb: Vector3<f32>) -> [f32; 4] {
     use palette::pixel::Srgb;
     let rgb: ::palette::Rgb = Srgb::new(rgb.x, rgb.y, rgb.z).into();
@@ -62,23 +69,68 @@ fn hex_corner(center: Xy, size: f32, angle: Rad<f32>, i: u8) -> Xy {
     center + rot.rotate_vector(Vector2 { x: 0.0, y: size })
 }
 
+/// Whatever holds a hex pressed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pointer {
+    Mouse,
+    Touch(u64),
+    Key(VirtualKeyCode),
+    /// Released by its pointer, but held by the sustain pedal
+    Sustain,
+    /// A step of a MIDI file being played
+    Playback(i32),
+    /// A key of the MIDI input, by channel and key
+    Midi(u8, u8),
+}
+
+/// What is written on the hexes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Labels {
+    None,
+    Steps,
+    Cents,
+    Names,
+}
+
+impl Labels {
+    pub fn next(self) -> Self {
+        match self {
+            Labels::None => Labels::Steps,
+            Labels::Steps => Labels::Cents,
+            Labels::Cents => Labels::Names,
+            Labels::Names => Labels::None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Hexes {
     pub size: Vector2<f32>,
     pub hex_size: f32,
     pub hex_gap: f32,
+    /// Position of the hex (0, 0) relative to the center of the window
+    pub offset: Xy,
     pub layout: Layout,
-    pub pressed: Vec<Qr<i32>>,
+    /// Steps added to every hex
+    pub transpose: i32,
+    pub pressed: Vec<(Pointer, Qr<i32>)>,
+    /// Hexes played from the computer keyboard
+    pub marked: Vec<Qr<i32>>,
+    pub labels: Labels,
 }
 
 impl Hexes {
     pub fn new(size: Vector2<f32>, layout: Layout) -> Self {
         Hexes {
             size,
-            hex_size: 80.0,
+            hex_size: HEX_SIZE,
             hex_gap: 2.0,
+            offset: Vector2::new(0.0, 0.0),
             layout,
+            transpose: 0,
             pressed: vec![],
+            marked: vec![],
+            labels: Labels::None,
         }
     }
 
@@ -90,30 +142,141 @@ impl Hexes {
         };
 
         let rgb = self.layout.colors[n as usize];
-        if self.pressed.contains(&c) {
+        if self.pressed.iter().any(|&(_, qr)| qr == c) {
             0.5 * rgb
         } else {
             rgb
         }
     }
 
+    fn label(&self, qr: Qr<i32>, names: &[String]) -> Option<String> {
+        let step = self.steps(qr);
+        let tuning = &self.layout.tuning;
+
+        match self.labels {
+            Labels::None => None,
+            Labels::Steps => Some(step.to_string()),
+            Labels::Cents => Some(format!("{:.0}", tuning.period_cents(step))),
+            Labels::Names => {
+                let n = names.len() as i32;
+                Some(names[((step % n + n) % n) as usize].clone())
+            },
+        }
+    }
+
     fn steps(&self, qr: Qr<i32>) -> i32 {
-        self.layout.q_steps * qr.x + self.layout.r_steps * qr.y
+        self.layout.q_steps * qr.x + self.layout.r_steps * qr.y + self.transpose
+    }
+
+    pub fn steps_at(&self, xy: Xy) -> i32 {
+        self.steps(self.hex_at(xy))
+    }
+
+    /// Of the hexes playing the step, the one closest to the center of the window
+    pub fn hex_for(&self, step: i32) -> Option<Qr<i32>> {
+        let center = self.hex_at(self.size / 2.0);
+        let radius = 32;
+
+        let mut hexes = vec![];
+        for dq in -radius..radius + 1 {
+            for dr in -radius..radius + 1 {
+                let qr = center + Vector2::new(dq, dr);
+                if self.steps(qr) == step {
+                    hexes.push((dq.abs() + dr.abs() + (dq + dr).abs(), qr))
+                }
+            }
+        }
+
+        hexes.into_iter().min_by_key(|h| h.0).map(|h| h.1)
     }
 
-    pub fn press(&mut self, xy: Xy) -> i32 {
+    /// Window coordinates to the ones of the grid
+    fn grid_xy(&self, xy: Xy) -> Xy {
         let xy = 2.0 * xy - self.size;
-        let xy = Vector2::new(xy.x, -xy.y);
+        Vector2::new(xy.x, -xy.y) - self.offset
+    }
+
+    fn hex_at(&self, xy: Xy) -> Qr<i32> {
+        let xy = self.grid_xy(xy);
+        round_qr(into_qr(xy, self.hex_size + self.hex_gap, self.layout.angle))
+    }
 
-        let qr = round_qr(into_qr(xy, self.hex_size + self.hex_gap, self.layout.angle));
-        self.pressed.push(qr);
+    /// Where the point is relative to the center of the hex, in hex sizes
+    fn offset_in(&self, qr: Qr<i32>, xy: Xy) -> Xy {
+        let xy = self.grid_xy(xy);
+        let center = into_xy(Vector2::new(qr.x as f32, qr.y as f32), self.hex_size + self.hex_gap, self.layout.angle);
+
+        (xy - center) / self.hex_size
+    }
+
+    /// Press the hex under the point, returns its step and where it was hit
+    pub fn press(&mut self, pointer: Pointer, xy: Xy) -> (i32, Xy) {
+        let qr = self.hex_at(xy);
+        let offset = self.offset_in(qr, xy);
+
+        (self.press_hex(pointer, qr), offset)
+    }
+
+    /// Where the pointer is relative to the hex it holds
+    pub fn offset(&self, pointer: Pointer, xy: Xy) -> Option<Xy> {
+        self.pressed.iter()
+            .find(|&&(p, _)| p == pointer)
+            .map(|&(_, qr)| self.offset_in(qr, xy))
+    }
+
+    pub fn press_hex(&mut self, pointer: Pointer, qr: Qr<i32>) -> i32 {
+        self.pressed.push((pointer, qr));
 
         self.steps(qr)
     }
 
+    pub fn holds(&self, pointer: Pointer) -> bool {
+        self.pressed.iter().any(|&(p, _)| p == pointer)
+    }
+
+    /// Whether the pointer holds a hex and has moved to another one
+    pub fn slid_off(&self, pointer: Pointer, xy: Xy) -> bool {
+        let qr = self.hex_at(xy);
+        self.pressed.iter().any(|&(p, c)| p == pointer && c != qr)
+    }
+
+    pub fn release(&mut self, pointer: Pointer) {
+        self.pressed.retain(|&(p, _)| p != pointer)
+    }
+
+    /// Pass the hexes held by one pointer to another
+    pub fn hand_over(&mut self, from: Pointer, to: Pointer) {
+        for pressed in &mut self.pressed {
+            if pressed.0 == from {
+                pressed.0 = to
+            }
+        }
+    }
+
     pub fn release_all(&mut self) {
         self.pressed.clear()
     }
+
+    /// Scale the grid around the point in the window
+    pub fn zoom(&mut self, factor: f32, xy: Xy) {
+        let hex_size = (self.hex_size * factor).max(10.0).min(400.0);
+        let factor = hex_size / self.hex_size;
+
+        let xy = 2.0 * xy - self.size;
+        let xy = Vector2::new(xy.x, -xy.y);
+        self.offset = xy - (xy - self.offset) * factor;
+        self.hex_size = hex_size;
+    }
+
+    /// Move the grid by a distance in window pixels
+    pub fn pan(&mut self, by: Xy) {
+        self.offset += 2.0 * Vector2::new(by.x, -by.y)
+    }
+
+    pub fn reset_view(&mut self) {
+        self.hex_size = HEX_SIZE;
+        self.offset = Vector2::new(0.0, 0.0);
+    }
 }
 
 impl Hexes {
@@ -121,29 +284,26 @@ impl Hexes {
         let width = self.size.x / self.size.y;
         let size = self.hex_size / self.size.y;
         let gap = self.hex_gap / self.size.y;
+        let offset = self.offset / self.size.y;
+        let names =
+            if self.labels == Labels::Names { self.layout.note_names() }
+            else { vec![] };
 
+        // The grid coordinates are linear, so the corners of the window bound them
+        let corners: Vec<_> = [(-width, -1.0), (width, -1.0), (-width, 1.0), (width, 1.0)].iter()
+            .map(|&(x, y)| round_qr(into_qr(Vector2::new(x, y) - offset, size + gap, self.layout.angle)))
+            .collect();
+        let (c0, c1) = corners.iter().fold((corners[0], corners[0]), |(c0, c1), c| (
+            Vector2::new(c0.x.min(c.x), c0.y.min(c.y)),
+            Vector2::new(c1.x.max(c.x), c1.y.max(c.y)),
+        ));
 
-        let angle =
-            if self.layout.angle.0 >= 0.0 {
-                -self.layout.angle
-            } else {
-                self.layout.angle
-            };
-        let c0 = round_qr(into_qr(Vector2::new(-width, -1.0), size, angle));
-        let c1 = round_qr(into_qr(Vector2::new(width, 1.0), size, angle));
-
-
-        fn rn(a: i32, b: i32) -> ::std::ops::Range<i32> {
-            if a < b { a..(b+1) }
-            else { b..(a+1) }
-        }
-
-        for q in rn(c0.x, c1.x) {
-            for r in rn(c0.y, c1.y) {
+        for q in (c0.x - 1)..(c1.x + 2) {
+            for r in (c0.y - 1)..(c1.y + 2) {
                 let qr = Vector2::new(q, r);
-                let xy = into_xy(Vector2::new(qr.x as f32, qr.y as f32), size + gap, self.layout.angle);
+                let xy = offset + into_xy(Vector2::new(qr.x as f32, qr.y as f32), size + gap, self.layout.angle);
                 let color = self.hex_color(qr);
-                let v_it = (0..6).map(|i| {
+                let hex = |size: f32, color: Color| (0..6).map(move |i| {
                     let c = hex_corner(xy, size, self.layout.angle, i);
                     super::Vertex {
                         pos: [c.x / width, c.y],
@@ -151,7 +311,25 @@ impl Hexes {
                     }
                 });
 
-                renderer.render_fan(v_it)
+                if self.marked.contains(&qr) {
+                    renderer.render_fan(hex(size, MARK_COLOR));
+                    renderer.render_fan(hex(0.9 * size, color))
+                } else {
+                    renderer.render_fan(hex(size, color))
+                }
+
+                if self.hex_size / 2.0 < MIN_LABEL_SIZE {
+                    continue
+                }
+                if let Some(text) = self.label(qr, &names) {
+                    // As tall as a third of the hex, narrower if it's long
+                    let len = text.chars().count() as f32;
+                    let height = (0.35 * size).min(1.4 * size * 7.0 / (6.0 * len - 1.0));
+                    let luma = 0.299 * color.x + 0.587 * color.y + 0.114 * color.z;
+                    let ink = if luma > 0.5 { [0.0, 0.0, 0.0, 1.0] } else { [1.0, 1.0, 1.0, 1.0] };
+
+                    renderer.render_text(&text, [xy.x, xy.y], height, width, ink)
+                }
             }
         }
     }